@@ -1,144 +1,267 @@
 extern crate pt;
 
-use image::{DynamicImage, RgbaImage};
-use pt::color::Color;
-use pt::point::Point;
-use pt::scene::{
-    Coloration, DirectionalLight, Element, Light, Material, Plane, Scene, Sphere, SphericalLight,
-    SurfaceType,
-};
-use pt::vector::Vector3;
-
-// ideally we could load the texture once, but somewhere downstream it gets consumed -- I think the
-// get_pixel call in the renderer?
-fn checkerboard() -> DynamicImage {
-    image::open("src\\bin\\checkerboard.png").unwrap()
+use pt::dither::Dither;
+use pt::framebuffer::Framebuffer;
+use pt::progressive::{render_progressive, StopConditions};
+use pt::sampling::SamplePattern;
+use pt::scene::{Scene, RECURSION_DEPTH_LIMIT};
+use pt::tone_mapping::ToneMapOperator;
+use pt::{Integrator, RenderOptions};
+use std::env;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+const USAGE: &str = "usage: main [options] <scene file>
+
+options:
+  -o, --output <path>        image to write, format chosen by extension (default: out.png);
+                             .exr, .hdr and .pfm keep the full range of linear values
+      --width <pixels>       override the scene's width
+      --height <pixels>      override the scene's height
+  -s, --samples <n>          samples per pixel (default: 1)
+      --pattern <pattern>    sample pattern: grid, jittered or random (default: grid, or
+                             jittered with --progressive so each pass samples new places)
+  -i, --integrator <name>    whitted, or path for path tracing (default: whitted)
+      --tone-map <operator>  how 8 bit output handles bright values: clamp, reinhard,
+                             extended-reinhard, aces or hable (default: clamp)
+      --white <luminance>    with extended-reinhard, the value that maps to white (default:
+                             the brightest pixel)
+      --exposure <stops>     brighten (or darken, if negative) before tone mapping (default: 0)
+      --dither <dither>      dithering for 8 bit output: none, ordered or blue-noise (default: none)
+  -t, --threads <n>          worker threads (default: number of cores)
+  -p, --progressive          render in passes of --samples each, saving the image after every
+                             pass, until stopped by --time, --max-samples or being killed
+      --time <seconds>       with --progressive, stop adding passes after this long
+      --max-samples <n>      with --progressive, stop at this many samples per pixel
+  -d, --depth <n>            override the scene's max recursion depth (at most 100)
+  -h, --help                 show this message";
+
+struct Args {
+    scene: String,
+    output: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    depth: Option<u32>,
+    options: RenderOptions,
+    progressive: Option<StopConditions>,
+}
+
+// Ok(None) means help was asked for
+fn parse_args(args: &[String]) -> Result<Option<Args>, String> {
+    let mut scene = None;
+    let mut output = "out.png".to_string();
+    let mut width = None;
+    let mut height = None;
+    let mut depth = None;
+    let mut options = RenderOptions::default();
+    let mut progressive = false;
+    let mut stop = StopConditions::default();
+    let mut white = None;
+    let mut pattern = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => output = value()?.clone(),
+            "--width" => width = Some(number(arg, value()?)?),
+            "--height" => height = Some(number(arg, value()?)?),
+            "-s" | "--samples" => options.samples_per_pixel = number(arg, value()?)?,
+            "--pattern" => pattern = Some(sample_pattern(value()?)?),
+            "-i" | "--integrator" => options.integrator = integrator(value()?)?,
+            "--tone-map" => options.tone_mapping.operator = tone_map_operator(value()?)?,
+            "--white" => white = Some(decimal(arg, value()?)?),
+            "--dither" => options.dither = dither(value()?)?,
+            "--exposure" => options.tone_mapping.exposure = decimal(arg, value()?)?,
+            "-t" | "--threads" => options.threads = number(arg, value()?)?,
+            "-p" | "--progressive" => progressive = true,
+            "--time" => stop.time_budget = Some(seconds(arg, value()?)?),
+            "--max-samples" => stop.max_samples = Some(number(arg, value()?)?),
+            "-d" | "--depth" => depth = Some(number(arg, value()?)?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if scene.is_none() => scene = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+
+    if width == Some(0) || height == Some(0) {
+        return Err("width and height must be at least 1".to_string());
+    }
+    if depth.is_some_and(|d| d > RECURSION_DEPTH_LIMIT) {
+        return Err(format!("depth must be at most {}", RECURSION_DEPTH_LIMIT));
+    }
+    if options.samples_per_pixel == 0 || options.threads == 0 {
+        return Err("samples and threads must be at least 1".to_string());
+    }
+    if let Some(white) = white {
+        match options.tone_mapping.operator {
+            ToneMapOperator::ExtendedReinhard { .. } => {
+                options.tone_mapping.operator =
+                    ToneMapOperator::ExtendedReinhard { white: Some(white) }
+            }
+            _ => return Err("--white needs --tone-map extended-reinhard".to_string()),
+        }
+    }
+    // Grid samples land in the same places every pass, so progressive passes would add nothing
+    // without random lighting samples
+    options.sample_pattern = match pattern {
+        Some(pattern) => pattern,
+        None if progressive => SamplePattern::Jittered,
+        None => options.sample_pattern,
+    };
+    if !progressive && (stop.time_budget.is_some() || stop.max_samples.is_some()) {
+        return Err("--time and --max-samples need --progressive".to_string());
+    }
+    Ok(Some(Args {
+        scene: scene.ok_or("no scene file given")?,
+        output,
+        width,
+        height,
+        depth,
+        options,
+        progressive: if progressive { Some(stop) } else { None },
+    }))
+}
+
+fn number<T: FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} expects a whole number but got `{}`", arg, value))
+}
+
+fn decimal(arg: &str, value: &str) -> Result<f32, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|v: &f32| v.is_finite())
+        .ok_or_else(|| format!("{} expects a number but got `{}`", arg, value))
+}
+
+fn seconds(arg: &str, value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|s: &f64| s.is_finite() && *s >= 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| format!("{} expects a number of seconds but got `{}`", arg, value))
 }
 
-fn scene() -> Scene {
-    Scene {
-        width: 1600,
-        height: 900,
-        fov: 90.0,
-        elements: vec![
-            Element::Sphere(Sphere {
-                center: Point {
-                    x: 0.3,
-                    y: 0.5,
-                    z: -3.0,
-                },
-                radius: 0.85,
-                material: Material {
-                    coloration: Coloration::Color(Color {
-                        red: 0.0,
-                        green: 1.0,
-                        blue: 0.0,
-                    }),
-                    albedo: 5.0,
-                    surface: SurfaceType::Reflective { reflectivity: 0.3 },
-                },
-            }),
-            Element::Sphere(Sphere {
-                center: Point {
-                    x: 3.5,
-                    y: -0.2,
-                    z: -6.0,
-                },
-                radius: 0.5,
-                material: Material {
-                    coloration: Coloration::Color(Color {
-                        red: 1.0,
-                        green: 0.0,
-                        blue: 0.0,
-                    }),
-                    albedo: 3.0,
-                    surface: SurfaceType::Reflective {
-                        reflectivity: 0.001,
-                    },
-                },
-            }),
-            Element::Sphere(Sphere {
-                center: Point {
-                    x: -2.5,
-                    y: 2.0,
-                    z: -6.0,
-                },
-                radius: 2.0,
-                material: Material {
-                    coloration: Coloration::Texture(checkerboard()),
-                    albedo: 6.0,
-                    surface: SurfaceType::Diffuse,
-                },
-            }),
-            Element::Plane(Plane {
-                origin: Point {
-                    x: 0.0,
-                    y: -2.0,
-                    z: 0.0,
-                },
-                normal: Vector3 {
-                    x: 0.0,
-                    y: -1.0,
-                    z: 0.0,
-                },
-                material: Material {
-                    coloration: Coloration::Texture(checkerboard()),
-                    albedo: 1.0,
-                    surface: SurfaceType::Reflective { reflectivity: 0.5 },
-                },
-            }),
-        ],
-        shadow_bias: 1e-13,
-        lights: vec![
-            Light::Directional(DirectionalLight {
-                direction: Vector3 {
-                    x: -0.8,
-                    y: -1.0,
-                    z: -0.4,
-                },
-                color: Color {
-                    red: 1.0,
-                    green: 1.0,
-                    blue: 1.0,
-                },
-                intensity: 0.8,
-            }),
-            Light::Directional(DirectionalLight {
-                direction: Vector3 {
-                    x: 0.8,
-                    y: -1.0,
-                    z: -0.4,
-                },
-                color: Color {
-                    red: 1.0,
-                    green: 0.2,
-                    blue: 0.2,
-                },
-                intensity: 0.6,
-            }),
-            Light::Spherical(SphericalLight {
-                position: Point {
-                    x: -1.0,
-                    y: 1.5,
-                    z: -1.0,
-                },
-                color: Color {
-                    red: 0.1,
-                    green: 0.1,
-                    blue: 0.8,
-                },
-                intensity: 100.0,
-            }),
-        ],
-        max_recursion_depth: 3,
+fn sample_pattern(value: &str) -> Result<SamplePattern, String> {
+    match value {
+        "grid" => Ok(SamplePattern::Grid),
+        "jittered" => Ok(SamplePattern::Jittered),
+        "random" => Ok(SamplePattern::Random),
+        _ => Err(format!(
+            "unknown sample pattern `{}`, expected grid, jittered or random",
+            value
+        )),
     }
 }
 
+fn tone_map_operator(value: &str) -> Result<ToneMapOperator, String> {
+    match value {
+        "clamp" => Ok(ToneMapOperator::Clamp),
+        "reinhard" => Ok(ToneMapOperator::Reinhard),
+        "extended-reinhard" => Ok(ToneMapOperator::ExtendedReinhard { white: None }),
+        "aces" => Ok(ToneMapOperator::Aces),
+        "hable" => Ok(ToneMapOperator::Hable),
+        _ => Err(format!(
+            "unknown tone mapping operator `{}`, expected clamp, reinhard, extended-reinhard, aces \
+             or hable",
+            value
+        )),
+    }
+}
+
+fn dither(value: &str) -> Result<Dither, String> {
+    match value {
+        "none" => Ok(Dither::None),
+        "ordered" => Ok(Dither::Ordered),
+        "blue-noise" => Ok(Dither::BlueNoise),
+        _ => Err(format!(
+            "unknown dither `{}`, expected none, ordered or blue-noise",
+            value
+        )),
+    }
+}
+
+fn integrator(value: &str) -> Result<Integrator, String> {
+    match value {
+        "whitted" => Ok(Integrator::Whitted),
+        "path" => Ok(Integrator::PathTracing),
+        _ => Err(format!(
+            "unknown integrator `{}`, expected whitted or path",
+            value
+        )),
+    }
+}
+
+fn run(mut args: Args) -> Result<(), String> {
+    let mut scene = Scene::from_file(&args.scene)
+        .map_err(|e| format!("couldn't load scene {}: {}", args.scene, e))?;
+    if let Some(width) = args.width {
+        scene.width = width;
+    }
+    if let Some(height) = args.height {
+        scene.height = height;
+    }
+    if let Some(depth) = args.depth {
+        scene.max_recursion_depth = depth;
+    }
+
+    let stop = match args.progressive.take() {
+        Some(stop) => stop,
+        None => {
+            let framebuffer = pt::render_framebuffer(&scene, &args.options);
+            return save(&framebuffer, &args);
+        }
+    };
+
+    // Save after every pass so there's always something to look at, and so killing the render
+    // leaves the last finished pass behind
+    let mut result = Ok(());
+    render_progressive(&scene, &args.options, &stop, |progress| {
+        eprintln!(
+            "pass {}: {} samples per pixel in {:.1}s",
+            progress.passes,
+            progress.samples_per_pixel,
+            progress.elapsed.as_secs_f64()
+        );
+        if result.is_ok() {
+            result = save(progress.image, &args);
+        }
+    });
+    result
+}
+
+fn save(framebuffer: &Framebuffer, args: &Args) -> Result<(), String> {
+    framebuffer
+        .save(
+            &args.output,
+            &args.options.tone_mapping,
+            args.options.dither,
+        )
+        .map_err(|e| format!("couldn't write {}: {}", args.output, e))
+}
+
 // Entry point for creating renderings.
 fn main() {
-    let scene = scene();
-    let img: RgbaImage = pt::render(&scene);
-    assert_eq!(scene.width, img.width());
-    assert_eq!(scene.height, img.height());
-    img.save("c:\\temp\\foo.png").unwrap();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match parse_args(&args) {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(args) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,397 @@
+// Bounding volume hierarchy, so a ray only has to be tested against the few items whose bounding
+// boxes it passes through rather than against everything. Built top down, splitting each node
+// where the surface area heuristic (SAH) says tracing through the children will be cheapest. See
+// https://pbr-book.org/3ed-2018/Primitives_and_Intersection_Acceleration/Bounding_Volume_Hierarchies
+//
+// The hierarchy only stores indexes; callers keep the items themselves and say how to intersect
+// one. Scenes use it over their elements and meshes over their faces.
+
+use crate::point::Point;
+use crate::rendering::{Hit, Ray};
+use crate::vector::Vector3;
+
+// Axis aligned bounding box
+#[derive(Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: Point,
+    pub max: Point,
+}
+
+impl Aabb {
+    // Contains nothing; growing it by anything gives that thing's bounds
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Point {
+                x: f64::INFINITY,
+                y: f64::INFINITY,
+                z: f64::INFINITY,
+            },
+            max: Point {
+                x: f64::NEG_INFINITY,
+                y: f64::NEG_INFINITY,
+                z: f64::NEG_INFINITY,
+            },
+        }
+    }
+
+    pub fn from_points(points: &[Point]) -> Aabb {
+        points.iter().fold(Aabb::empty(), |bounds, p| {
+            bounds.union(&Aabb { min: *p, max: *p })
+        })
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Point {
+                x: self.min.x.min(other.min.x),
+                y: self.min.y.min(other.min.y),
+                z: self.min.z.min(other.min.z),
+            },
+            max: Point {
+                x: self.max.x.max(other.max.x),
+                y: self.max.y.max(other.max.y),
+                z: self.max.z.max(other.max.z),
+            },
+        }
+    }
+
+    pub fn centroid(&self) -> Point {
+        self.min + (self.max - self.min) * 0.5
+    }
+
+    fn surface_area(&self) -> f64 {
+        let size = self.max - self.min;
+        if size.x < 0.0 {
+            return 0.0; // empty
+        }
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    // Distance along the ray to where it enters the box, if it does so before `max_distance`.
+    // Zero if the ray starts inside.
+    fn entry_distance(
+        &self,
+        ray: &Ray,
+        inverse_direction: &Vector3,
+        max_distance: f64,
+    ) -> Option<f64> {
+        // Slab test. A zero direction component gives an infinite inverse, and then NaN if the
+        // origin is exactly on that slab's boundary; f64::min/max ignore NaN which treats the ray
+        // as inside that slab.
+        let mut near: f64 = 0.0;
+        let mut far = max_distance;
+        for axis in 0..3 {
+            let origin = component(&ray.origin, axis);
+            let inverse = vector_component(inverse_direction, axis);
+            let t1 = (component(&self.min, axis) - origin) * inverse;
+            let t2 = (component(&self.max, axis) - origin) * inverse;
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+        }
+        if near <= far {
+            Some(near)
+        } else {
+            None
+        }
+    }
+}
+
+fn component(p: &Point, axis: usize) -> f64 {
+    match axis {
+        0 => p.x,
+        1 => p.y,
+        _ => p.z,
+    }
+}
+
+fn vector_component(v: &Vector3, axis: usize) -> f64 {
+    match axis {
+        0 => v.x,
+        1 => v.y,
+        _ => v.z,
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    bounds: Aabb,
+    // Leaf: `count` items starting at items[first]. Interior (count == 0): the left child is the
+    // next node and the right child is nodes[first].
+    first: usize,
+    count: usize,
+}
+
+#[derive(Debug)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    items: Vec<usize>,
+    unbounded: Vec<usize>, // items with no bounding box (e.g. planes), always tested
+}
+
+const BINS: usize = 16;
+const MAX_LEAF_SIZE: usize = 4;
+// Cost of visiting a node relative to intersecting one item
+const TRAVERSAL_COST: f64 = 0.5;
+
+impl Bvh {
+    // `bounds[i]` is the bounding box of item i, or None if it's unbounded
+    pub fn build(bounds: &[Option<Aabb>]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            items: Vec::new(),
+            unbounded: Vec::new(),
+        };
+        for (i, b) in bounds.iter().enumerate() {
+            match b {
+                Some(_) => bvh.items.push(i),
+                None => bvh.unbounded.push(i),
+            }
+        }
+        if !bvh.items.is_empty() {
+            let boxes: Vec<Aabb> = bounds
+                .iter()
+                .map(|b| b.unwrap_or_else(Aabb::empty))
+                .collect();
+            let centroids: Vec<Point> = boxes.iter().map(Aabb::centroid).collect();
+            bvh.build_node(&boxes, &centroids, 0, bvh.items.len());
+        }
+        bvh
+    }
+
+    // Builds the node for items[start..end], returning its index
+    fn build_node(
+        &mut self,
+        boxes: &[Aabb],
+        centroids: &[Point],
+        start: usize,
+        end: usize,
+    ) -> usize {
+        let index = self.nodes.len();
+        let items = &self.items[start..end];
+        let bounds = items.iter().fold(Aabb::empty(), |b, &i| b.union(&boxes[i]));
+        self.nodes.push(Node {
+            bounds,
+            first: start,
+            count: end - start,
+        });
+
+        let split = if items.len() > 1 {
+            self.best_split(boxes, centroids, start, end, bounds.surface_area())
+        } else {
+            None
+        };
+        let (axis, min, extent, split_bin) = match split {
+            Some(split) => split,
+            None => return index, // stays a leaf
+        };
+
+        // Partition so everything left of the split comes first
+        let mut mid = start;
+        for i in start..end {
+            let item = self.items[i];
+            if bin(component(&centroids[item], axis), min, extent) < split_bin {
+                self.items.swap(i, mid);
+                mid += 1;
+            }
+        }
+
+        self.build_node(boxes, centroids, start, mid);
+        let right = self.build_node(boxes, centroids, mid, end);
+        self.nodes[index].first = right;
+        self.nodes[index].count = 0;
+        index
+    }
+
+    // Returns (axis, centroid min, centroid extent, first bin of the right child) for the
+    // cheapest split of items[start..end], or None if a leaf is cheaper
+    fn best_split(
+        &self,
+        boxes: &[Aabb],
+        centroids: &[Point],
+        start: usize,
+        end: usize,
+        surface_area: f64,
+    ) -> Option<(usize, f64, f64, usize)> {
+        let items = &self.items[start..end];
+        let centroid_bounds =
+            Aabb::from_points(&items.iter().map(|&i| centroids[i]).collect::<Vec<_>>());
+
+        let mut best: Option<(f64, usize, f64, f64, usize)> = None;
+        for axis in 0..3 {
+            let min = component(&centroid_bounds.min, axis);
+            let extent = component(&centroid_bounds.max, axis) - min;
+            if extent <= 0.0 {
+                continue; // all centroids in the same place along this axis
+            }
+
+            let mut bin_bounds = [Aabb::empty(); BINS];
+            let mut bin_counts = [0usize; BINS];
+            for &item in items {
+                let b = bin(component(&centroids[item], axis), min, extent);
+                bin_bounds[b] = bin_bounds[b].union(&boxes[item]);
+                bin_counts[b] += 1;
+            }
+
+            // Sweep from the right to get the cost of everything right of each split, then from
+            // the left to combine it with everything left of it
+            let mut right_area = [0.0; BINS];
+            let mut right_count = [0; BINS];
+            let mut bounds = Aabb::empty();
+            let mut count = 0;
+            for b in (1..BINS).rev() {
+                bounds = bounds.union(&bin_bounds[b]);
+                count += bin_counts[b];
+                right_area[b] = bounds.surface_area();
+                right_count[b] = count;
+            }
+            let mut bounds = Aabb::empty();
+            let mut count = 0;
+            for split in 1..BINS {
+                bounds = bounds.union(&bin_bounds[split - 1]);
+                count += bin_counts[split - 1];
+                if count == 0 || right_count[split] == 0 {
+                    continue;
+                }
+                let cost = TRAVERSAL_COST
+                    + (bounds.surface_area() * count as f64
+                        + right_area[split] * right_count[split] as f64)
+                        / surface_area;
+                if best.is_none_or(|(best_cost, ..)| cost < best_cost) {
+                    best = Some((cost, axis, min, extent, split));
+                }
+            }
+        }
+
+        match best {
+            Some((cost, axis, min, extent, split)) => {
+                if cost < items.len() as f64 || items.len() > MAX_LEAF_SIZE {
+                    Some((axis, min, extent, split))
+                } else {
+                    None
+                }
+            }
+            None => None,
+        }
+    }
+
+    // Finds the nearest hit along the ray. `intersect(i)` tests the ray against item i. Ties go to
+    // the lowest index, so the result is the same as testing every item in order.
+    pub fn intersect(
+        &self,
+        ray: &Ray,
+        mut intersect: impl FnMut(usize) -> Option<Hit>,
+    ) -> Option<(usize, Hit)> {
+        let mut nearest: Option<(usize, Hit)> = None;
+        let mut consider = |item: usize, nearest: &mut Option<(usize, Hit)>| {
+            if let Some(hit) = intersect(item) {
+                let closer = match nearest {
+                    Some((i, h)) => {
+                        hit.distance < h.distance || (hit.distance == h.distance && item < *i)
+                    }
+                    None => true,
+                };
+                if closer {
+                    *nearest = Some((item, hit));
+                }
+            }
+        };
+
+        for &item in &self.unbounded {
+            consider(item, &mut nearest);
+        }
+        if self.nodes.is_empty() {
+            return nearest;
+        }
+
+        let inverse_direction = Vector3 {
+            x: ray.direction.x.recip(),
+            y: ray.direction.y.recip(),
+            z: ray.direction.z.recip(),
+        };
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let max_distance = nearest.map_or(f64::INFINITY, |(_, hit)| hit.distance);
+            if node
+                .bounds
+                .entry_distance(ray, &inverse_direction, max_distance)
+                .is_none()
+            {
+                continue;
+            }
+            if node.count > 0 {
+                for &item in &self.items[node.first..node.first + node.count] {
+                    consider(item, &mut nearest);
+                }
+            } else {
+                // Visit the nearer child first; a hit there lets us skip the other one entirely
+                let (left, right) = (index + 1, node.first);
+                let distance = |child: usize| {
+                    self.nodes[child]
+                        .bounds
+                        .entry_distance(ray, &inverse_direction, max_distance)
+                        .unwrap_or(f64::INFINITY)
+                };
+                if distance(left) <= distance(right) {
+                    stack.push(right);
+                    stack.push(left);
+                } else {
+                    stack.push(left);
+                    stack.push(right);
+                }
+            }
+        }
+        nearest
+    }
+
+    // Whether anything is hit closer than `max_distance`. `blocks(i)` says whether item i is.
+    // Stops at the first such item rather than looking for the nearest, which is all shadow rays
+    // need.
+    pub fn occluded(
+        &self,
+        ray: &Ray,
+        max_distance: f64,
+        mut blocks: impl FnMut(usize) -> bool,
+    ) -> bool {
+        if self.unbounded.iter().any(|&item| blocks(item)) {
+            return true;
+        }
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let inverse_direction = Vector3 {
+            x: ray.direction.x.recip(),
+            y: ray.direction.y.recip(),
+            z: ray.direction.z.recip(),
+        };
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node
+                .bounds
+                .entry_distance(ray, &inverse_direction, max_distance)
+                .is_none()
+            {
+                continue;
+            }
+            if node.count > 0 {
+                if self.items[node.first..node.first + node.count]
+                    .iter()
+                    .any(|&item| blocks(item))
+                {
+                    return true;
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(index + 1);
+            }
+        }
+        false
+    }
+}
+
+// Which of the BINS equal slices of min..min+extent the value falls in
+fn bin(value: f64, min: f64, extent: f64) -> usize {
+    (((value - min) / extent * BINS as f64) as usize).min(BINS - 1)
+}
@@ -0,0 +1,117 @@
+use crate::point::Point;
+use crate::vector::Vector3;
+use std::fmt;
+
+// Which extent of the image the camera's fov spans
+#[derive(Copy, Clone, Debug)]
+pub enum FovAxis {
+    Horizontal,
+    Vertical,
+    Diagonal,
+}
+
+// Built with Camera::new, which checks the view direction and up make sense, so the basis always
+// exists
+#[derive(Debug)]
+pub struct Camera {
+    position: Point, // the eye
+    look_at: Point,
+    up: Vector3, // doesn't need to be perpendicular to the view direction, just not parallel
+    fov: f64,    // degrees
+    fov_axis: FovAxis,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraError {
+    NoViewDirection, // look_at is the camera's position
+    UpAlongView,     // up is parallel to the view direction, so doesn't say which way is up
+}
+
+impl fmt::Display for CameraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CameraError::NoViewDirection => write!(f, "look_at must be different from position"),
+            CameraError::UpAlongView => write!(f, "up must not be parallel to the view direction"),
+        }
+    }
+}
+
+impl std::error::Error for CameraError {}
+
+impl Default for Camera {
+    // Camera at the origin looking down -z with +y up
+    fn default() -> Camera {
+        Camera {
+            position: Point::zero(),
+            look_at: Point {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            up: Vector3 {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            fov: 90.0,
+            fov_axis: FovAxis::Vertical,
+        }
+    }
+}
+
+impl Camera {
+    pub fn new(
+        position: Point,
+        look_at: Point,
+        up: Vector3,
+        fov: f64,
+        fov_axis: FovAxis,
+    ) -> Result<Camera, CameraError> {
+        let view = look_at - position;
+        if view.length() == 0.0 {
+            return Err(CameraError::NoViewDirection);
+        }
+        // A zero length up normalizes to NaN, which counts as parallel too
+        let across = view.normalize().cross(&up.normalize()).length();
+        if across.is_nan() || across <= 1e-9 {
+            return Err(CameraError::UpAlongView);
+        }
+        Ok(Camera {
+            position,
+            look_at,
+            up,
+            fov,
+            fov_axis,
+        })
+    }
+
+    pub fn position(&self) -> Point {
+        self.position
+    }
+
+    pub fn look_at(&self) -> Point {
+        self.look_at
+    }
+
+    pub fn up(&self) -> Vector3 {
+        self.up
+    }
+
+    pub fn fov(&self) -> f64 {
+        self.fov
+    }
+
+    pub fn fov_axis(&self) -> FovAxis {
+        self.fov_axis
+    }
+
+    // Returns orthonormal (right, up, forward) vectors for the camera. The default camera gives
+    // the world axes: (+x, +y, -z).
+    pub fn basis(&self) -> (Vector3, Vector3, Vector3) {
+        let forward = (self.look_at - self.position).normalize();
+        let right = forward.cross(&self.up).normalize();
+        // self.up might not be perpendicular to forward, so recompute it
+        let up = right.cross(&forward);
+        (right, up, forward)
+    }
+}
@@ -1,5 +1,6 @@
 use image::Rgba;
 use std::ops::{Add, Mul};
+use std::sync::OnceLock;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Color {
@@ -8,39 +9,86 @@ pub struct Color {
     pub blue: f32,
 }
 
-const GAMMA: f32 = 2.2;
+// The sRGB transfer functions: a short linear segment near black, then a 2.4 power curve. Close to
+// a plain 2.2 gamma, but not the same, particularly in the darks.
+// https://en.wikipedia.org/wiki/SRGB#Transfer_function_(%22gamma%22)
+pub fn srgb_encode(linear: f32) -> f32 {
+    if linear <= 0.003_130_8 {
+        linear * 12.92
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    }
+}
 
-fn gamma_encode(linear: f32) -> f32 {
-    linear.powf(1.0 / GAMMA)
+pub fn srgb_decode(encoded: f32) -> f32 {
+    if encoded <= 0.040_45 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
 }
 
-fn gamma_decode(encoded: f32) -> f32 {
-    encoded.powf(GAMMA)
+// Decoding 8 bit values is done for every texture lookup, and there are only 256 of them
+fn srgb_decode_table() -> &'static [f32; 256] {
+    static TABLE: OnceLock<[f32; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0.0; 256];
+        for (i, value) in table.iter_mut().enumerate() {
+            *value = srgb_decode(i as f32 / 255.0);
+        }
+        table
+    })
 }
 
 impl Color {
+    pub fn black() -> Color {
+        Color {
+            red: 0.0,
+            green: 0.0,
+            blue: 0.0,
+        }
+    }
+
+    // sRGB encodes, rounding to the nearest 8 bit value
     pub fn to_rgba(&self) -> Rgba<u8> {
+        self.to_rgba_dithered(0.5)
+    }
+
+    // sRGB encodes to 8 bits, rounding up where the fraction left over is at least `threshold`
+    // (0.0..1.0). Varying the threshold from pixel to pixel (see dither.rs) trades the banding of
+    // always rounding the same way for fine noise.
+    pub fn to_rgba_dithered(&self, threshold: f32) -> Rgba<u8> {
+        let quantize = |linear: f32| {
+            let encoded = srgb_encode(linear.clamp(0.0, 1.0)) * 255.0;
+            (encoded + 1.0 - threshold).floor().min(255.0) as u8
+        };
         Rgba([
-            (gamma_encode(self.red) * 255.0) as u8,
-            (gamma_encode(self.green) * 255.0) as u8,
-            (gamma_encode(self.blue) * 255.0) as u8,
+            quantize(self.red),
+            quantize(self.green),
+            quantize(self.blue),
             255,
         ])
     }
 
     pub fn from_rgba(rgba: Rgba<u8>) -> Color {
+        let table = srgb_decode_table();
         Color {
-            red: gamma_decode((rgba.data[0] as f32) / 255.0),
-            green: gamma_decode((rgba.data[1] as f32) / 255.0),
-            blue: gamma_decode((rgba.data[2] as f32) / 255.0),
+            red: table[rgba.data[0] as usize],
+            green: table[rgba.data[1] as usize],
+            blue: table[rgba.data[2] as usize],
         }
     }
 
+    // Perceived brightness, using the Rec. 709 weights for linear sRGB
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.red + 0.7152 * self.green + 0.0722 * self.blue
+    }
+
     pub fn clamp(&self) -> Color {
         Color {
-            red: self.red.min(1.0).max(0.0),
-            green: self.green.min(1.0).max(0.0),
-            blue: self.blue.min(1.0).max(0.0),
+            red: self.red.clamp(0.0, 1.0),
+            green: self.green.clamp(0.0, 1.0),
+            blue: self.blue.clamp(0.0, 1.0),
         }
     }
 }
@@ -0,0 +1,144 @@
+// Dithering for 8 bit output. Smooth gradients, especially dark ones, only have a handful of 8 bit
+// levels to work with and rounding every pixel the same way turns them into visible bands. Rounding
+// up or down based on a threshold that varies from pixel to pixel breaks the bands up into fine
+// noise that the eye averages out.
+
+use crate::sampling::Rng;
+use std::sync::OnceLock;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Dither {
+    None, // always round to nearest
+    // 8x8 Bayer matrix. Cheap and even, but leaves a visible cross hatch pattern.
+    Ordered,
+    // Tiled 64x64 blue noise: thresholds without low frequency clumps, so the noise looks fine
+    // grained and has no pattern
+    BlueNoise,
+}
+
+impl Dither {
+    // Rounding threshold (0.0..1.0) for the given pixel, for Color::to_rgba_dithered
+    pub fn threshold(&self, x: u32, y: u32) -> f32 {
+        match *self {
+            Dither::None => 0.5,
+            Dither::Ordered => {
+                let rank = BAYER[(y % 8) as usize][(x % 8) as usize];
+                (rank as f32 + 0.5) / 64.0
+            }
+            Dither::BlueNoise => {
+                let ranks = blue_noise();
+                let rank = ranks[(y % BLUE_NOISE_SIZE) as usize * BLUE_NOISE_SIZE as usize
+                    + (x % BLUE_NOISE_SIZE) as usize];
+                (rank as f32 + 0.5) / ranks.len() as f32
+            }
+        }
+    }
+}
+
+const BAYER: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+const BLUE_NOISE_SIZE: u32 = 64;
+
+// Rank of each pixel in the blue noise tile, generated the first time it's needed
+fn blue_noise() -> &'static [u32] {
+    static RANKS: OnceLock<Vec<u32>> = OnceLock::new();
+    RANKS.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE as usize))
+}
+
+// Ulichney's void and cluster method (https://cv.ulichney.com/papers/1993-void-cluster.pdf).
+// Pixels are ranked by adding them one at a time, each time in the emptiest spot, where emptiness
+// is measured by a Gaussian blur of the pixels added so far, wrapping around the edges so the tile
+// repeats seamlessly. The paper switches to ranking clusters of empty pixels past the halfway
+// point; carrying on filling voids is a common simplification that makes little visible difference.
+fn void_and_cluster(size: usize) -> Vec<u32> {
+    let count = size * size;
+
+    // Contribution of a pixel to the energy of another at each (wrapped) offset
+    let sigma = 1.5f32;
+    let mut kernel = vec![0.0f32; count];
+    for dy in 0..size {
+        for dx in 0..size {
+            let wrapped = |d: usize| d.min(size - d) as f32;
+            let (x, y) = (wrapped(dx), wrapped(dy));
+            kernel[dy * size + dx] = (-(x * x + y * y) / (2.0 * sigma * sigma)).exp();
+        }
+    }
+    let mut energy = vec![0.0f32; count];
+    let update = |energy: &mut Vec<f32>, pixel: usize, sign: f32| {
+        let (px, py) = (pixel % size, pixel / size);
+        for y in 0..size {
+            for x in 0..size {
+                let offset = ((y + size - py) % size) * size + (x + size - px) % size;
+                energy[y * size + x] += sign * kernel[offset];
+            }
+        }
+    };
+    let tightest_cluster = |energy: &[f32], on: &[bool]| {
+        (0..count)
+            .filter(|&i| on[i])
+            .max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+    let largest_void = |energy: &[f32], on: &[bool]| {
+        (0..count)
+            .filter(|&i| !on[i])
+            .min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            .unwrap()
+    };
+
+    // Start from a random tenth of the pixels, then even them out by moving the pixel in the
+    // tightest cluster to the largest void until that stops changing anything (which in practice
+    // happens long before the limit)
+    let mut rng = Rng::new(0);
+    let mut on = vec![false; count];
+    let initial = count / 10;
+    let mut placed = 0;
+    while placed < initial {
+        let pixel = rng.next_u32() as usize % count;
+        if !on[pixel] {
+            on[pixel] = true;
+            update(&mut energy, pixel, 1.0);
+            placed += 1;
+        }
+    }
+    for _ in 0..count {
+        let cluster = tightest_cluster(&energy, &on);
+        on[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        let void = largest_void(&energy, &on);
+        on[void] = true;
+        update(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    // The initial pixels are ranked by taking them away again, tightest cluster first; the rest by
+    // filling in voids
+    let mut ranks = vec![0u32; count];
+    let initial_on = on.clone();
+    let initial_energy = energy.clone();
+    for rank in (0..initial).rev() {
+        let cluster = tightest_cluster(&energy, &on);
+        on[cluster] = false;
+        update(&mut energy, cluster, -1.0);
+        ranks[cluster] = rank as u32;
+    }
+    let (mut on, mut energy) = (initial_on, initial_energy);
+    for rank in initial..count {
+        let void = largest_void(&energy, &on);
+        on[void] = true;
+        update(&mut energy, void, 1.0);
+        ranks[void] = rank as u32;
+    }
+    ranks
+}
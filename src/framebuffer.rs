@@ -0,0 +1,81 @@
+// Linear colours for every pixel, as the renderer worked them out. Unlike an RgbaImage nothing has
+// been tone mapped or sRGB encoded yet, so they can still be averaged with further samples.
+
+use crate::color::Color;
+use crate::dither::Dither;
+use crate::hdr;
+use crate::tone_mapping::ToneMapping;
+use image::RgbaImage;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+#[derive(Clone, Debug)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<Color>, // row by row from the top left
+}
+
+impl Framebuffer {
+    // All black
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Color::black(); width as usize * height as usize],
+        }
+    }
+
+    pub fn get_pixel(&self, x: u32, y: u32) -> Color {
+        self.pixels[self.index(x, y)]
+    }
+
+    pub fn put_pixel(&mut self, x: u32, y: u32, color: Color) {
+        let index = self.index(x, y);
+        self.pixels[index] = color;
+    }
+
+    pub fn pixels(&self) -> &[Color] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [Color] {
+        &mut self.pixels
+    }
+
+    // Tone maps and sRGB encodes into an 8 bit image for display or saving
+    pub fn to_rgba_image(&self, tone_mapping: &ToneMapping, dither: Dither) -> RgbaImage {
+        let mapped = tone_mapping.apply(self);
+        RgbaImage::from_fn(self.width, self.height, |x, y| {
+            mapped
+                .get_pixel(x, y)
+                .to_rgba_dithered(dither.threshold(x, y))
+        })
+    }
+
+    // Saves in the format the path's extension asks for. .exr, .hdr and .pfm files keep the linear
+    // values as they are; anything else is tone mapped and sRGB encoded to 8 bits.
+    pub fn save<P: AsRef<Path>>(
+        &self,
+        path: P,
+        tone_mapping: &ToneMapping,
+        dither: Dither,
+    ) -> io::Result<()> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("exr") => hdr::write_exr(self, BufWriter::new(File::create(path)?)),
+            Some("hdr") => hdr::write_radiance_hdr(self, BufWriter::new(File::create(path)?)),
+            Some("pfm") => hdr::write_pfm(self, BufWriter::new(File::create(path)?)),
+            _ => self.to_rgba_image(tone_mapping, dither).save(path),
+        }
+    }
+
+    fn index(&self, x: u32, y: u32) -> usize {
+        y as usize * self.width as usize + x as usize
+    }
+}
@@ -0,0 +1,214 @@
+// Writers for floating point image formats, so renders can be saved with their full range of linear
+// light values instead of being squeezed into 8 gamma encoded bits. All three formats are simple
+// enough to write by hand without pulling in crates for them:
+//
+// - OpenEXR (.exr): uncompressed 32 bit float scanlines.
+//   https://openexr.com/en/latest/OpenEXRFileLayout.html
+// - Radiance RGBE (.hdr): 8 bit mantissas sharing an exponent, run length encoded.
+//   https://www.graphics.cornell.edu/~bjw/rgbe.html
+// - Portable float map (.pfm): raw 32 bit floats. https://www.pauldebevec.com/Research/HDR/PFM/
+
+use crate::color::Color;
+use crate::framebuffer::Framebuffer;
+use std::io::{self, Write};
+
+pub fn write_exr(framebuffer: &Framebuffer, mut out: impl Write) -> io::Result<()> {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    let mut header = Vec::new();
+    header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]); // magic number
+    header.extend_from_slice(&2u32.to_le_bytes()); // version 2, single part scanline file
+
+    // Channels have to be listed in alphabetical order, and pixel data follows the same order
+    let mut channels = Vec::new();
+    for name in &["B", "G", "R"] {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        channels.extend_from_slice(&2i32.to_le_bytes()); // FLOAT
+        channels.extend_from_slice(&[0, 0, 0, 0]); // pLinear and reserved
+        channels.extend_from_slice(&1i32.to_le_bytes()); // x sampling
+        channels.extend_from_slice(&1i32.to_le_bytes()); // y sampling
+    }
+    channels.push(0);
+    exr_attribute(&mut header, "channels", "chlist", &channels);
+    exr_attribute(&mut header, "compression", "compression", &[0]); // none
+    let mut window = Vec::new();
+    for value in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&value.to_le_bytes());
+    }
+    exr_attribute(&mut header, "dataWindow", "box2i", &window);
+    exr_attribute(&mut header, "displayWindow", "box2i", &window);
+    exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]); // increasing y
+    exr_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    );
+    header.push(0);
+
+    // Uncompressed files have one scanline per block, each preceded by its y and byte count. The
+    // header is followed by a table of where each block starts.
+    let block_size = 8 + 3 * 4 * width as u64;
+    let first_block = header.len() as u64 + 8 * height as u64;
+    for y in 0..height as u64 {
+        header.extend_from_slice(&(first_block + y * block_size).to_le_bytes());
+    }
+    out.write_all(&header)?;
+
+    let mut block = Vec::with_capacity(block_size as usize);
+    for y in 0..height {
+        block.clear();
+        block.extend_from_slice(&(y as i32).to_le_bytes());
+        block.extend_from_slice(&((block_size - 8) as i32).to_le_bytes());
+        for channel in 0..3 {
+            for x in 0..width {
+                let color = framebuffer.get_pixel(x, y);
+                let value = match channel {
+                    0 => color.blue,
+                    1 => color.green,
+                    _ => color.red,
+                };
+                block.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        out.write_all(&block)?;
+    }
+    out.flush()
+}
+
+fn exr_attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+pub fn write_radiance_hdr(framebuffer: &Framebuffer, mut out: impl Write) -> io::Result<()> {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    write!(
+        out,
+        "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n",
+        height, width
+    )?;
+
+    let mut scanline = Vec::with_capacity(width as usize);
+    let mut encoded = Vec::new();
+    for y in 0..height {
+        scanline.clear();
+        scanline.extend((0..width).map(|x| rgbe(framebuffer.get_pixel(x, y))));
+        encoded.clear();
+        if !(8..=0x7fff).contains(&width) {
+            // Too narrow or wide to run length encode; written flat
+            for pixel in &scanline {
+                encoded.extend_from_slice(pixel);
+            }
+        } else {
+            // Without the encoding, a pixel that happened to start with 2, 2 would be mistaken
+            // for the start of an encoded scanline. Each of the four bytes is encoded separately.
+            encoded.extend_from_slice(&[2, 2, (width >> 8) as u8, width as u8]);
+            for component in 0..4 {
+                let bytes: Vec<u8> = scanline.iter().map(|p| p[component]).collect();
+                run_length_encode(&bytes, &mut encoded);
+            }
+        }
+        out.write_all(&encoded)?;
+    }
+    out.flush()
+}
+
+// Shared exponent encoding: the brightest component sets the exponent and all three are stored as
+// 8 bit fractions of it
+fn rgbe(color: Color) -> [u8; 4] {
+    let (r, g, b) = (
+        color.red.max(0.0),
+        color.green.max(0.0),
+        color.blue.max(0.0),
+    );
+    let brightest = r.max(g).max(b);
+    if brightest.is_nan() || brightest.is_infinite() || brightest < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // brightest = mantissa * 2^exponent, with the mantissa in 0.5..1
+    let mut exponent = brightest.log2().floor() as i32 + 1;
+    let mut mantissa = brightest / 2f32.powi(exponent);
+    if mantissa >= 1.0 {
+        mantissa /= 2.0;
+        exponent += 1;
+    } else if mantissa < 0.5 {
+        mantissa *= 2.0;
+        exponent -= 1;
+    }
+    let scale = mantissa * 256.0 / brightest;
+    [
+        (r * scale) as u8,
+        (g * scale) as u8,
+        (b * scale) as u8,
+        (exponent + 128) as u8,
+    ]
+}
+
+// Radiance's scheme: a count byte over 128 means repeat the next byte count - 128 times, otherwise
+// that many literal bytes follow
+fn run_length_encode(bytes: &[u8], out: &mut Vec<u8>) {
+    const MIN_RUN: usize = 4; // shorter runs aren't worth breaking up literals for
+    let mut i = 0;
+    while i < bytes.len() {
+        let run = bytes[i..]
+            .iter()
+            .take(127)
+            .take_while(|&&b| b == bytes[i])
+            .count();
+        if run >= MIN_RUN {
+            out.push(128 + run as u8);
+            out.push(bytes[i]);
+            i += run;
+            continue;
+        }
+        // Literals up to the next run worth encoding
+        let mut end = i;
+        while end < bytes.len() && end - i < 128 {
+            let run = bytes[end..]
+                .iter()
+                .take(MIN_RUN)
+                .take_while(|&&b| b == bytes[end])
+                .count();
+            if run >= MIN_RUN {
+                break;
+            }
+            end += 1;
+        }
+        out.push((end - i) as u8);
+        out.extend_from_slice(&bytes[i..end]);
+        i = end;
+    }
+}
+
+pub fn write_pfm(framebuffer: &Framebuffer, mut out: impl Write) -> io::Result<()> {
+    // A negative scale means little endian. Rows go from the bottom up.
+    write!(
+        out,
+        "PF\n{} {}\n-1.0\n",
+        framebuffer.width, framebuffer.height
+    )?;
+    let mut row = Vec::with_capacity(12 * framebuffer.width as usize);
+    for y in (0..framebuffer.height).rev() {
+        row.clear();
+        for x in 0..framebuffer.width {
+            let color = framebuffer.get_pixel(x, y);
+            for value in &[color.red, color.green, color.blue] {
+                row.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        out.write_all(&row)?;
+    }
+    out.flush()
+}
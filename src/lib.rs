@@ -2,32 +2,157 @@
 
 extern crate image;
 
+mod bvh;
+pub mod camera;
 pub mod color;
+pub mod dither;
+pub mod framebuffer;
+pub mod hdr;
+pub mod obj;
+mod path_tracing;
 pub mod point;
+pub mod procedural;
+pub mod progressive;
 mod rendering;
+pub mod sampling;
 pub mod scene;
+pub mod scene_file;
+pub mod texture;
+pub mod tone_mapping;
 pub mod vector;
 
 use image::Rgba;
 use image::RgbaImage;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
-use crate::rendering::{get_color, Ray};
+use crate::color::Color;
+use crate::dither::Dither;
+use crate::framebuffer::Framebuffer;
+use crate::path_tracing::trace_path;
+use crate::rendering::{get_color, Ray, RayCone};
+use crate::sampling::{pixel_offsets, Rng, SamplePattern};
 use crate::scene::Scene;
+use crate::tone_mapping::ToneMapping;
+
+// How the light reaching the camera is worked out
+#[derive(Copy, Clone, Debug)]
+pub enum Integrator {
+    // Direct light plus mirror reflections and refraction, up to the scene's max recursion depth.
+    // Quick and noise free apart from soft shadows, but with no light bouncing between surfaces.
+    Whitted,
+    // Monte Carlo path tracing with indirect light. Needs many samples per pixel.
+    PathTracing,
+}
+
+#[derive(Clone, Debug)]
+pub struct RenderOptions {
+    pub threads: usize, // number of worker threads; 1 renders on the calling thread
+    pub samples_per_pixel: u32,
+    pub sample_pattern: SamplePattern,
+    pub integrator: Integrator,
+    pub tone_mapping: ToneMapping, // used when producing 8 bit images
+    pub dither: Dither,            // likewise
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            threads: thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            samples_per_pixel: 1,
+            sample_pattern: SamplePattern::Grid,
+            integrator: Integrator::Whitted,
+            tone_mapping: ToneMapping::default(),
+            dither: Dither::None,
+        }
+    }
+}
 
 pub fn render(scene: &Scene) -> RgbaImage {
-    let mut image = RgbaImage::new(scene.width, scene.height);
-    let sky = Rgba([178, 212, 255, 255]);
-
-    for y in 0..scene.height {
-        for x in 0..scene.width {
-            let ray = Ray::create_prime(x, y, scene);
-            let intersection = scene.trace(&ray);
-            let color = match intersection {
-                Some(intersection) => get_color(scene, &ray, &intersection, 0).to_rgba(),
-                _ => sky,
-            };
-            image.put_pixel(x, y, color);
+    render_with_options(scene, &RenderOptions::default())
+}
+
+pub fn render_with_options(scene: &Scene, options: &RenderOptions) -> RgbaImage {
+    render_framebuffer(scene, options).to_rgba_image(&options.tone_mapping, options.dither)
+}
+
+// Renders to linear floating point colours, without tone mapping or sRGB encoding them, e.g. for
+// saving as HDR or compositing
+pub fn render_framebuffer(scene: &Scene, options: &RenderOptions) -> Framebuffer {
+    render_pass(scene, options, 0, None).expect("can't be cancelled without a flag")
+}
+
+// Renders every pixel once with `options.samples_per_pixel` samples, drawn from the random
+// sequence for `pass`. Returns None if `cancel` is set part way through.
+pub(crate) fn render_pass(
+    scene: &Scene,
+    options: &RenderOptions,
+    pass: u32,
+    cancel: Option<&AtomicBool>,
+) -> Option<Framebuffer> {
+    let mut framebuffer = Framebuffer::new(scene.width, scene.height);
+    let cancelled = || cancel.is_some_and(|c| c.load(Ordering::Relaxed));
+    let threads = options.threads.max(1);
+    if threads == 1 {
+        for y in 0..scene.height {
+            if cancelled() {
+                return None;
+            }
+            for x in 0..scene.width {
+                framebuffer.put_pixel(x, y, render_pixel(scene, options, x, y, pass));
+            }
+        }
+        return Some(framebuffer);
+    }
+
+    // Workers grab the next unrendered scanline until there are none left. Each pixel is
+    // independent of every other so the result doesn't depend on which thread rendered what.
+    let next_row = AtomicU32::new(0);
+    let framebuffer = Mutex::new(framebuffer);
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let y = next_row.fetch_add(1, Ordering::Relaxed);
+                if y >= scene.height || cancelled() {
+                    break;
+                }
+                let row: Vec<Color> = (0..scene.width)
+                    .map(|x| render_pixel(scene, options, x, y, pass))
+                    .collect();
+                let mut framebuffer = framebuffer.lock().unwrap();
+                for (x, color) in row.into_iter().enumerate() {
+                    framebuffer.put_pixel(x as u32, y, color);
+                }
+            });
         }
+    });
+    if cancelled() {
+        return None;
+    }
+    Some(framebuffer.into_inner().unwrap())
+}
+
+fn render_pixel(scene: &Scene, options: &RenderOptions, x: u32, y: u32, pass: u32) -> Color {
+    let sky = Color::from_rgba(Rgba([178, 212, 255, 255]));
+    let mut rng = Rng::for_pixel_pass(x, y, pass);
+    let offsets = pixel_offsets(options.sample_pattern, options.samples_per_pixel, &mut rng);
+
+    // Average in linear space; sRGB encoding only happens once we have the final pixel colour
+    let mut color = Color::black();
+    let cone = RayCone::primary(scene, offsets.len() as u32);
+    for &(offset_x, offset_y) in &offsets {
+        let ray = Ray::create_prime(x as f64 + offset_x, y as f64 + offset_y, scene);
+        color = color
+            + match options.integrator {
+                Integrator::Whitted => match scene.trace(&ray) {
+                    Some(intersection) => get_color(scene, &ray, &cone, &intersection, 0, &mut rng),
+                    _ => sky,
+                },
+                Integrator::PathTracing => trace_path(scene, ray, cone, sky, &mut rng),
+            };
     }
-    image
+    color * (offsets.len() as f32).recip()
 }
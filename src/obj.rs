@@ -0,0 +1,682 @@
+// Wavefront OBJ/MTL import (http://paulbourke.net/dataformats/obj/,
+// http://paulbourke.net/dataformats/mtl/)
+//
+// We read the subset that maps onto our elements: vertex positions, normals and texture coords,
+// polygon faces, groups of faces using different materials, and the diffuse colour/texture and
+// specular reflection parts of MTL materials. Everything else (curves, line elements, smoothing
+// groups, ambient terms, ...) is ignored.
+
+use crate::color::Color;
+use crate::point::Point;
+use crate::scene::{
+    Coloration, Element, Material, Mesh, NormalMap, Parameter, SurfaceType, TextureCoords, Vertex,
+    DEFAULT_BUMP_SCALE,
+};
+use crate::texture::{Texture, TextureAddressing, TextureCache, TextureEncoding};
+use crate::vector::Vector3;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io { path, error } => {
+                write!(f, "couldn't read {}: {}", path.display(), error)
+            }
+            ObjError::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{} line {}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+type Result<T> = std::result::Result<T, ObjError>;
+
+// Loads the model at `path` as one mesh per material, all sharing a single vertex buffer. Faces
+// that don't name a material (or name one the MTL files don't define) get `default_material`.
+// Polygons are split into triangle fans, so they should be convex.
+// Textures are loaded through `textures`, so ones already loaded (e.g. by other OBJ files or the
+// scene) aren't decoded again
+pub fn load<P: AsRef<Path>>(
+    path: P,
+    default_material: &Material,
+    textures: &TextureCache,
+) -> Result<Vec<Element>> {
+    let path = path.as_ref();
+    let source = read(path)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut texture_coords = Vec::new();
+    let mut materials = HashMap::new();
+
+    // OBJ indexes positions, normals and texture coords separately but our vertices bundle them,
+    // so every distinct combination used by a face becomes one vertex
+    let mut vertices = Vec::new();
+    let mut vertex_indexes: HashMap<(usize, Option<usize>, Option<usize>), usize> = HashMap::new();
+    // faces grouped by material name, in the order the materials were first used
+    let mut groups: Vec<(Option<String>, Vec<[usize; 3]>)> = vec![(None, Vec::new())];
+
+    for (index, line) in source.lines().enumerate() {
+        let line = Line::new(path, index + 1, line);
+        match line.keyword() {
+            Some("v") => positions.push(line.point()?),
+            Some("vn") => normals.push(line.vector()?.normalize()),
+            Some("vt") => {
+                let (u, v) = (line.number(1)?, line.optional_number(2)?.unwrap_or(0.0));
+                // OBJ's v axis points up the image but our texture y axis points down it
+                texture_coords.push(TextureCoords { x: u, y: 1.0 - v });
+            }
+            Some("f") => {
+                let mut corners = Vec::new();
+                for corner in line.arguments() {
+                    let (p, t, n) =
+                        line.corner(corner, positions.len(), texture_coords.len(), normals.len())?;
+                    let vertex = *vertex_indexes.entry((p, t, n)).or_insert_with(|| {
+                        vertices.push(Vertex {
+                            position: positions[p],
+                            normal: n.map(|n| normals[n]),
+                            texture_coords: t.map(|t| texture_coords[t]),
+                        });
+                        vertices.len() - 1
+                    });
+                    corners.push(vertex);
+                }
+                if corners.len() < 3 {
+                    return Err(line.error("a face needs at least 3 vertices"));
+                }
+                let faces = &mut groups.last_mut().unwrap().1;
+                for i in 1..corners.len() - 1 {
+                    faces.push([corners[0], corners[i], corners[i + 1]]);
+                }
+            }
+            Some("usemtl") => {
+                let name = line.rest()?.to_string();
+                match groups.iter().position(|(n, _)| n.as_deref() == Some(&name)) {
+                    // move the group to the end so it receives the faces that follow
+                    Some(i) => {
+                        let group = groups.remove(i);
+                        groups.push(group);
+                    }
+                    None => groups.push((Some(name), Vec::new())),
+                }
+            }
+            Some("mtllib") => {
+                for file in line.arguments() {
+                    materials.extend(load_mtl(&base_dir.join(file), textures)?);
+                }
+            }
+            _ => {} // comments, groups, objects, smoothing groups and anything we don't support
+        }
+    }
+
+    let vertices = Arc::new(vertices);
+    Ok(groups
+        .into_iter()
+        .filter(|(_, faces)| !faces.is_empty())
+        .map(|(name, faces)| {
+            let material = name
+                .and_then(|name| materials.get(&name))
+                .unwrap_or(default_material)
+                .clone();
+            Element::Mesh(Mesh {
+                vertices: Arc::clone(&vertices),
+                faces,
+                material,
+                bvh: None,
+            })
+        })
+        .collect())
+}
+
+// Reads the materials in an MTL file. Kd/map_Kd become the coloration. Transparent materials
+// (dissolve below 1 or a glass illumination model) become refractive, using Ni as the refractive
+// index. Otherwise materials whose illumination model includes ray traced reflection (illum 3 and
+// up) become reflective with the specular colour (Ks) as the reflectivity. Models with highlights
+// (illum 2 and up) also use Ks and the specular exponent (Ns) for those. `norm` is a normal map and
+// `bump` (or `map_bump`) a bump map, its heights scaled by any `-bm`.
+pub fn load_mtl(path: &Path, textures: &TextureCache) -> Result<HashMap<String, Material>> {
+    let source = read(path)?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, MtlMaterial)> = None;
+    for (index, line) in source.lines().enumerate() {
+        let line = Line::new(path, index + 1, line);
+        let keyword = match line.keyword() {
+            Some(keyword) => keyword,
+            None => continue,
+        };
+        if keyword == "newmtl" {
+            if let Some((name, material)) = current.take() {
+                materials.insert(name, material.into_material());
+            }
+            current = Some((line.rest()?.to_string(), MtlMaterial::default()));
+            continue;
+        }
+        let material = match current {
+            Some((_, ref mut material)) => material,
+            None if [
+                "Kd", "map_Kd", "Ks", "Ns", "Ni", "d", "Tr", "illum", "norm", "bump", "map_bump",
+            ]
+            .contains(&keyword) =>
+            {
+                return Err(line.error("material property before any newmtl"))
+            }
+            None => continue,
+        };
+        match keyword {
+            "Kd" => material.diffuse = line.color()?,
+            "Ks" => material.specular = line.color()?,
+            "Ns" => material.specular_exponent = line.number(1)?,
+            "Ni" => material.optical_density = Some(line.number(1)?),
+            "d" => material.dissolve = Some(line.number(1)?),
+            "Tr" => material.dissolve = Some(1.0 - line.number::<f32>(1)?),
+            "illum" => material.illumination_model = line.number(1)?,
+            "map_Kd" => {
+                material.texture = Some(map(&line, base_dir, textures, TextureEncoding::Srgb)?)
+            }
+            "norm" => {
+                let texture = map(&line, base_dir, textures, TextureEncoding::Linear)?;
+                material.normal_map = Some(NormalMap::Normals(texture));
+            }
+            "bump" | "map_bump" => {
+                let texture = map(&line, base_dir, textures, TextureEncoding::Linear)?;
+                material.normal_map = Some(NormalMap::Bump {
+                    height: Coloration::Texture(texture),
+                    scale: DEFAULT_BUMP_SCALE * bump_multiplier(&line)?,
+                });
+            }
+            _ => {}
+        }
+    }
+    if let Some((name, material)) = current {
+        materials.insert(name, material.into_material());
+    }
+    Ok(materials)
+}
+
+// `map_Kd [options] file`, or any other map. Of the options, `-s u [v]` scales and `-o u [v]`
+// offsets the texture coords and `-clamp on` clamps rather than repeats the texture. Others are
+// skipped over; the file name is always last.
+fn map(
+    line: &Line,
+    base_dir: &Path,
+    textures: &TextureCache,
+    encoding: TextureEncoding,
+) -> Result<Texture> {
+    let arguments: Vec<&str> = line.arguments().collect();
+    let file = arguments.last().copied().unwrap_or("");
+    let path = base_dir.join(file);
+    let mut texture = textures
+        .load(&path, encoding)
+        .map_err(|e| line.error(&format!("couldn't load texture {}: {}", path.display(), e)))?;
+
+    let options = &arguments[..arguments.len().saturating_sub(1)];
+    let mut i = 0;
+    while i < options.len() {
+        // Up to three numbers follow -s and -o; the third is for 3D textures, which we don't have
+        let numbers: Vec<f32> = options[i + 1..]
+            .iter()
+            .take(3)
+            .map_while(|n| n.parse().ok())
+            .collect();
+        match options[i] {
+            "-s" | "-o" if numbers.is_empty() => {
+                return Err(line.error(&format!("{} needs at least 1 value", options[i])))
+            }
+            "-s" => texture.transform.scale = (numbers[0], numbers.get(1).copied().unwrap_or(1.0)),
+            "-o" => texture.transform.offset = (numbers[0], numbers.get(1).copied().unwrap_or(0.0)),
+            "-clamp" if options.get(i + 1) == Some(&"on") => {
+                texture.addressing = TextureAddressing::Clamp
+            }
+            _ => {}
+        }
+        i += 1 + numbers.len();
+    }
+    Ok(texture)
+}
+
+// The `-bm mult` option of a bump map, which scales its heights. 1 if not given.
+fn bump_multiplier(line: &Line) -> Result<f32> {
+    let arguments: Vec<&str> = line.arguments().collect();
+    match arguments.iter().position(|&a| a == "-bm") {
+        Some(i) if i + 2 < arguments.len() => arguments[i + 1].parse().map_err(|_| {
+            line.error(&format!(
+                "expected a number but found `{}`",
+                arguments[i + 1]
+            ))
+        }),
+        Some(_) => Err(line.error("-bm needs a value")),
+        None => Ok(1.0),
+    }
+}
+
+struct MtlMaterial {
+    diffuse: Color,
+    specular: Color,
+    specular_exponent: f32,
+    texture: Option<Texture>,
+    normal_map: Option<NormalMap>, // from norm, or bump/map_bump
+    optical_density: Option<f32>,  // aka refractive index
+    dissolve: Option<f32>,         // 1.0 is opaque
+    illumination_model: u32,
+}
+
+impl Default for MtlMaterial {
+    fn default() -> MtlMaterial {
+        MtlMaterial {
+            diffuse: Color {
+                red: 0.8,
+                green: 0.8,
+                blue: 0.8,
+            },
+            specular: Color::black(),
+            specular_exponent: 32.0,
+            texture: None,
+            normal_map: None,
+            optical_density: None,
+            dissolve: None,
+            illumination_model: 2,
+        }
+    }
+}
+
+impl MtlMaterial {
+    fn into_material(self) -> Material {
+        let coloration = match self.texture {
+            Some(texture) => Coloration::Texture(texture),
+            None => Coloration::Color(self.diffuse),
+        };
+        let reflectivity = (self.specular.red + self.specular.green + self.specular.blue) / 3.0;
+        let glass = matches!(self.illumination_model, 4 | 6 | 7 | 9);
+        let dissolve = self.dissolve.unwrap_or(1.0);
+        let surface = if glass || dissolve < 1.0 {
+            SurfaceType::Refractive {
+                index: Parameter::Constant(self.optical_density.unwrap_or(1.0)),
+                // glass models with no dissolve given are taken to be fully transparent
+                transparency: Parameter::Constant(if dissolve < 1.0 {
+                    1.0 - dissolve
+                } else {
+                    1.0
+                }),
+            }
+        } else if self.illumination_model >= 3 && reflectivity > 0.0 {
+            SurfaceType::Reflective {
+                reflectivity: Parameter::Constant(reflectivity),
+            }
+        } else {
+            SurfaceType::Diffuse
+        };
+        // illum 0 and 1 have no highlights
+        let specular_color = if self.illumination_model >= 2 {
+            self.specular
+        } else {
+            Color::black()
+        };
+        Material {
+            coloration,
+            albedo: Parameter::Constant(1.0),
+            surface,
+            specular_color,
+            specular_exponent: Parameter::Constant(self.specular_exponent),
+            normal_map: self.normal_map,
+        }
+    }
+}
+
+fn read(path: &Path) -> Result<String> {
+    fs::read_to_string(path).map_err(|error| ObjError::Io {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+// One line of an OBJ or MTL file, for pulling values out with decent error messages
+struct Line<'a> {
+    path: &'a Path,
+    number: usize,
+    text: &'a str,
+}
+
+impl<'a> Line<'a> {
+    fn new(path: &'a Path, number: usize, text: &'a str) -> Line<'a> {
+        let text = match text.find('#') {
+            Some(comment) => &text[..comment],
+            None => text,
+        };
+        Line { path, number, text }
+    }
+
+    fn error(&self, message: &str) -> ObjError {
+        ObjError::Parse {
+            path: self.path.to_path_buf(),
+            line: self.number,
+            message: message.to_string(),
+        }
+    }
+
+    fn keyword(&self) -> Option<&'a str> {
+        self.text.split_whitespace().next()
+    }
+
+    fn arguments(&self) -> impl Iterator<Item = &'a str> {
+        self.text.split_whitespace().skip(1)
+    }
+
+    // Everything after the keyword, for names that might contain spaces
+    fn rest(&self) -> Result<&'a str> {
+        let keyword = self.keyword().unwrap_or("");
+        let rest = self.text.trim_start()[keyword.len()..].trim();
+        if rest.is_empty() {
+            Err(self.error(&format!("{} needs a name", keyword)))
+        } else {
+            Ok(rest)
+        }
+    }
+
+    fn optional_number<T: FromStr>(&self, index: usize) -> Result<Option<T>> {
+        match self.text.split_whitespace().nth(index) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| self.error(&format!("expected a number but found `{}`", value))),
+            None => Ok(None),
+        }
+    }
+
+    fn number<T: FromStr>(&self, index: usize) -> Result<T> {
+        self.optional_number(index)?.ok_or_else(|| {
+            self.error(&format!(
+                "{} needs at least {} values",
+                self.keyword().unwrap_or(""),
+                index
+            ))
+        })
+    }
+
+    fn point(&self) -> Result<Point> {
+        Ok(Point {
+            x: self.number(1)?,
+            y: self.number(2)?,
+            z: self.number(3)?,
+        })
+    }
+
+    fn vector(&self) -> Result<Vector3> {
+        Ok(Vector3 {
+            x: self.number(1)?,
+            y: self.number(2)?,
+            z: self.number(3)?,
+        })
+    }
+
+    fn color(&self) -> Result<Color> {
+        let red = self.number(1)?;
+        // a single value means grey
+        Ok(Color {
+            red,
+            green: self.optional_number(2)?.unwrap_or(red),
+            blue: self.optional_number(3)?.unwrap_or(red),
+        })
+    }
+
+    // Resolves one `position[/texture coords][/normal]` corner of a face to zero based indexes.
+    // Indexes count from 1, or backwards from the latest value if negative.
+    fn corner(
+        &self,
+        corner: &str,
+        positions: usize,
+        texture_coords: usize,
+        normals: usize,
+    ) -> Result<(usize, Option<usize>, Option<usize>)> {
+        let mut parts = corner.split('/');
+        let position = self.index(parts.next(), positions, "vertex")?;
+        let texture_coords = self.index(parts.next(), texture_coords, "texture coord")?;
+        let normal = self.index(parts.next(), normals, "normal")?;
+        match position {
+            Some(position) => Ok((position, texture_coords, normal)),
+            None => Err(self.error(&format!("face corner `{}` has no vertex", corner))),
+        }
+    }
+
+    fn index(&self, index: Option<&str>, count: usize, what: &str) -> Result<Option<usize>> {
+        let index = match index {
+            None | Some("") => return Ok(None),
+            Some(index) => index,
+        };
+        let value: i64 = index
+            .parse()
+            .map_err(|_| self.error(&format!("expected a {} index but found `{}`", what, index)))?;
+        let resolved = if value < 0 {
+            count as i64 + value
+        } else {
+            value - 1
+        };
+        if resolved < 0 || resolved >= count as i64 {
+            return Err(self.error(&format!(
+                "{} index {} is out of range; there are {} so far",
+                what, value, count
+            )));
+        }
+        Ok(Some(resolved as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::Scene;
+    use crate::scene_file::SceneError;
+
+    // Writes `files` to a fresh directory and loads model.obj from there through a scene file
+    fn parse(test: &str, files: &[(&str, &str)]) -> std::result::Result<Scene, SceneError> {
+        let dir = std::env::temp_dir().join(format!("pt-obj-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        let texture = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenes/checkerboard.png");
+        fs::copy(texture, dir.join("checkerboard.png")).unwrap();
+        let scene = Scene::parse("width 1\nheight 1\nobj {\n    file model.obj\n}\n", &dir);
+        fs::remove_dir_all(&dir).unwrap();
+        scene
+    }
+
+    fn load_scene(test: &str, files: &[(&str, &str)]) -> Scene {
+        parse(test, files).unwrap()
+    }
+
+    fn meshes(scene: &Scene) -> Vec<&Mesh> {
+        scene
+            .elements
+            .iter()
+            .map(|element| match element {
+                Element::Mesh(mesh) => mesh,
+                other => panic!("expected a mesh, got {:?}", other),
+            })
+            .collect()
+    }
+
+    fn positions(mesh: &Mesh) -> Vec<Vec<(f64, f64)>> {
+        mesh.faces
+            .iter()
+            .map(|face| {
+                face.iter()
+                    .map(|&i| (mesh.vertices[i].position.x, mesh.vertices[i].position.y))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn splits_polygons_into_fans() {
+        let scene = load_scene(
+            "fan",
+            &[(
+                "model.obj",
+                "v 0 0 0\nv 1 0 0\nv 2 1 0\nv 1 2 0\nv 0 1 0\nf 1 2 3 4 5\n",
+            )],
+        );
+        let meshes = meshes(&scene);
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(
+            positions(meshes[0]),
+            vec![
+                vec![(0.0, 0.0), (1.0, 0.0), (2.0, 1.0)],
+                vec![(0.0, 0.0), (2.0, 1.0), (1.0, 2.0)],
+                vec![(0.0, 0.0), (1.0, 2.0), (0.0, 1.0)],
+            ]
+        );
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_latest() {
+        let scene = load_scene(
+            "negative",
+            &[(
+                "model.obj",
+                "v 9 9 9
+                 v 0 0 0
+                 v 1 0 0
+                 v 0 1 0
+                 vt 0.25 0.75
+                 vn 0 0 2
+                 f -3/-1/-1 -2/-1/-1 -1/-1/-1
+                 v 5 5 0
+                 f -4 -1 -2",
+            )],
+        );
+        let mesh = meshes(&scene)[0];
+        assert_eq!(
+            positions(mesh),
+            vec![
+                vec![(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)],
+                vec![(0.0, 0.0), (5.0, 5.0), (0.0, 1.0)],
+            ]
+        );
+        let first = &mesh.vertices[mesh.faces[0][0]];
+        assert_eq!(first.normal.unwrap().z, 1.0);
+        let coords = first.texture_coords.unwrap();
+        assert_eq!((coords.x, coords.y), (0.25, 0.25));
+    }
+
+    #[test]
+    fn groups_faces_by_material() {
+        let scene = load_scene(
+            "usemtl",
+            &[
+                (
+                    "materials.mtl",
+                    "newmtl red\nKd 1 0 0\nnewmtl blue\nKd 0 0 1\n",
+                ),
+                (
+                    "model.obj",
+                    "mtllib materials.mtl
+                     v 0 0 0
+                     v 1 0 0
+                     v 0 1 0
+                     f 1 2 3
+                     usemtl red
+                     f 1 2 3
+                     usemtl blue
+                     f 1 2 3
+                     usemtl red
+                     f 3 2 1
+                     usemtl missing
+                     f 1 2 3",
+                ),
+            ],
+        );
+        let meshes = meshes(&scene);
+        let colors: Vec<_> = meshes
+            .iter()
+            .map(|mesh| match mesh.material.coloration {
+                Coloration::Color(c) => (c.red, c.green, c.blue),
+                ref other => panic!("expected a colour, got {:?}", other),
+            })
+            .collect();
+        // Faces without a known material get the default one, white here; red is moved to the end
+        // when it's used again
+        assert_eq!(
+            colors,
+            vec![
+                (1.0, 1.0, 1.0),
+                (0.0, 0.0, 1.0),
+                (1.0, 0.0, 0.0),
+                (1.0, 1.0, 1.0)
+            ]
+        );
+        let faces: Vec<_> = meshes.iter().map(|mesh| mesh.faces.len()).collect();
+        assert_eq!(faces, vec![1, 1, 2, 1]);
+        // The vertex buffer is shared
+        assert!(Arc::ptr_eq(&meshes[0].vertices, &meshes[2].vertices));
+    }
+
+    #[test]
+    fn reads_map_options() {
+        let scene = load_scene(
+            "map",
+            &[
+                (
+                    "materials.mtl",
+                    "newmtl tiles
+                     map_Kd -s 2 3 1 -blendu off -o 0.5 -clamp on checkerboard.png
+                     bump -bm 4 checkerboard.png",
+                ),
+                (
+                    "model.obj",
+                    "mtllib materials.mtl\nusemtl tiles\nv 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n",
+                ),
+            ],
+        );
+        let material = &meshes(&scene)[0].material;
+        match material.coloration {
+            Coloration::Texture(ref texture) => {
+                assert_eq!(texture.transform.scale, (2.0, 3.0));
+                assert_eq!(texture.transform.offset, (0.5, 0.0));
+                assert!(matches!(texture.addressing, TextureAddressing::Clamp));
+            }
+            ref other => panic!("expected a texture, got {:?}", other),
+        }
+        match material.normal_map {
+            Some(NormalMap::Bump { scale, .. }) => assert_eq!(scale, DEFAULT_BUMP_SCALE * 4.0),
+            ref other => panic!("expected a bump map, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_bad_faces_with_their_line() {
+        let result = parse("bad", &[("model.obj", "v 0 0 0\nv 1 0 0\nf 1 2\n")]);
+        match result {
+            Err(SceneError::Parse { line, message, .. }) => {
+                assert_eq!(line, 4);
+                assert!(message.ends_with("model.obj line 3: a face needs at least 3 vertices"));
+            }
+            other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+        }
+    }
+}
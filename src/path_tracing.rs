@@ -0,0 +1,229 @@
+// Monte Carlo path tracing. Rather than the fixed set of rays the Whitted-style tracer in
+// rendering.rs sends out, each camera ray is followed as it bounces around the scene, picking one
+// random direction at every diffuse surface. That brings in light arriving indirectly: colour
+// bleeding between surfaces, light bounced off the floor into shadows, caustics under glass. It
+// needs many samples per pixel before the noise averages away.
+//
+// At diffuse surfaces direct light is estimated in two ways: by picking a point on each light
+// (next event estimation), and by the bounce ray happening to run into a light. Multiple importance
+// sampling weighs the two, so small lights are found by sampling them and big ones by bouncing.
+// See https://pbr-book.org/3ed-2018/Light_Transport_I_Surface_Reflection
+
+use crate::color::Color;
+use crate::point::Point;
+use crate::rendering::{fresnel, shading_normal, texture_lookup, Intersectable, Ray, RayCone};
+use crate::sampling::{self, Rng};
+use crate::scene::{Scene, ShadingParameters, SurfaceType};
+use crate::vector::Vector3;
+use std::f64::consts::PI;
+
+// Bounces before Russian roulette may end a path. Until then every path carries on.
+const MIN_BOUNCES: u32 = 3;
+// Hard limit, in case a path keeps bouncing between mirrors
+const MAX_BOUNCES: u32 = 64;
+
+// Light arriving along the camera ray. `sky` is the light from rays that leave the scene.
+pub fn trace_path(scene: &Scene, ray: Ray, cone: RayCone, sky: Color, rng: &mut Rng) -> Color {
+    let mut radiance = Color::black();
+    // How much of the light arriving at the current ray's origin makes it back to the camera
+    let mut throughput = Color {
+        red: 1.0,
+        green: 1.0,
+        blue: 1.0,
+    };
+    let mut ray = ray;
+    let mut cone = cone;
+    // Density the current ray's direction was picked with, to weigh any light it runs into against
+    // sampling that light directly. None when light sampling couldn't have produced it (the
+    // camera ray, mirror and glass bounces), in which case it gets the light's full weight.
+    let mut bounce_pdf: Option<f64> = None;
+
+    for bounce in 0..MAX_BOUNCES {
+        let intersection = scene.trace(&ray);
+        let surface_distance = intersection.as_ref().map_or(f64::INFINITY, |i| i.distance);
+
+        // Lights aren't part of the scene's geometry; they glow but don't block anything
+        for light in &scene.lights {
+            if let Some(hit) = light.hit(&ray) {
+                if hit.distance < surface_distance {
+                    let weight = bounce_pdf.map_or(1.0, |pdf| power_heuristic(pdf, hit.pdf));
+                    radiance =
+                        radiance + throughput * light.color() * (hit.radiance * weight as f32);
+                }
+            }
+        }
+
+        let intersection = match intersection {
+            Some(intersection) => intersection,
+            None => {
+                radiance = radiance + throughput * sky;
+                break;
+            }
+        };
+
+        let hit_point = ray.origin + (ray.direction * intersection.distance);
+        let element = intersection.element;
+        let material = element.material();
+        let surface_normal = element.surface_normal(&hit_point, intersection.primitive);
+        let lookup = texture_lookup(&ray, &cone, &intersection, hit_point, surface_normal);
+        let shading = material.shading_parameters(&lookup);
+        let surface_normal = shading_normal(&intersection, &lookup, surface_normal);
+        // Diffuse bounces scatter far wider than the cone, but it still blurs textures seen
+        // indirectly by about the right amount
+        cone = cone.after(intersection.distance);
+        // The side of the surface the ray arrived on
+        let facing_normal = if ray.direction.dot(&surface_normal) > 0.0 {
+            -surface_normal
+        } else {
+            surface_normal
+        };
+
+        // Materials mix a diffuse part with a mirror or glass part. Rather than following both,
+        // pick one with probability equal to its share; the probability and the share cancel.
+        match material.surface {
+            SurfaceType::Reflective { ref reflectivity }
+                if rng.next_f64() < reflectivity.value(&lookup) as f64 =>
+            {
+                ray = Ray::create_reflection(
+                    facing_normal,
+                    ray.direction,
+                    hit_point,
+                    scene.shadow_bias,
+                );
+                bounce_pdf = None;
+            }
+            SurfaceType::Refractive {
+                ref index,
+                ref transparency,
+            } if rng.next_f64() < transparency.value(&lookup) as f64 => {
+                let index = index.value(&lookup);
+                // Same again: reflect or refract with the Fresnel probabilities
+                let kr = fresnel(ray.direction, surface_normal, index);
+                let transmission = if rng.next_f64() < kr {
+                    None
+                } else {
+                    Ray::create_transmission(
+                        surface_normal,
+                        ray.direction,
+                        hit_point,
+                        scene.shadow_bias,
+                        index,
+                    )
+                };
+                ray = transmission.unwrap_or_else(|| {
+                    Ray::create_reflection(
+                        facing_normal,
+                        ray.direction,
+                        hit_point,
+                        scene.shadow_bias,
+                    )
+                });
+                throughput = throughput * shading.color;
+                bounce_pdf = None;
+            }
+            _ => {
+                let view_direction = -ray.direction;
+                radiance = radiance
+                    + throughput
+                        * direct_light(
+                            scene,
+                            &shading,
+                            hit_point,
+                            facing_normal,
+                            view_direction,
+                            rng,
+                        );
+
+                let (x, y, z) = sampling::cosine_hemisphere(rng);
+                let (tangent, bitangent) = facing_normal.orthonormal_basis();
+                let direction = tangent * x + bitangent * y + facing_normal * z;
+                // The cosine and the density (cosine / pi) cancel, leaving pi
+                let reflectance = brdf(&shading, facing_normal, direction, view_direction);
+                throughput = throughput * reflectance * PI as f32;
+                ray = Ray {
+                    origin: hit_point + (facing_normal * scene.shadow_bias),
+                    direction,
+                };
+                bounce_pdf = Some(z / PI);
+            }
+        }
+
+        // Russian roulette: end dim paths early at random, boosting the ones that survive so that
+        // on average the result is unchanged
+        if bounce + 1 >= MIN_BOUNCES {
+            let brightest = throughput.red.max(throughput.green).max(throughput.blue);
+            let survival = (brightest as f64).min(0.95);
+            if rng.next_f64() >= survival {
+                break;
+            }
+            throughput = throughput * (survival as f32).recip();
+        }
+    }
+    radiance
+}
+
+// Light from one sample of each light, reflected towards `view_direction`
+fn direct_light(
+    scene: &Scene,
+    shading: &ShadingParameters,
+    hit_point: Point,
+    surface_normal: Vector3,
+    view_direction: Vector3,
+    rng: &mut Rng,
+) -> Color {
+    let mut color = Color::black();
+    for light in &scene.lights {
+        let sample = light.sample(&hit_point, rng);
+        let cos_theta = surface_normal.dot(&sample.direction);
+        if cos_theta <= 0.0 {
+            continue;
+        }
+        let shadow_ray = Ray {
+            origin: hit_point + (surface_normal * scene.shadow_bias),
+            direction: sample.direction,
+        };
+        if scene.occluded(&shadow_ray, sample.distance) {
+            continue;
+        }
+        let weight = sample
+            .pdf
+            .map_or(1.0, |pdf| power_heuristic(pdf, cos_theta / PI));
+        let reflectance = brdf(shading, surface_normal, sample.direction, view_direction);
+        color =
+            color + reflectance * light.color() * (sample.intensity * (cos_theta * weight) as f32);
+    }
+    color
+}
+
+// Fraction of light arriving from `light_direction` that's reflected towards `view_direction`
+// (per steradian): Lambertian diffuse plus the same Blinn-Phong highlight the Whitted tracer uses.
+// Unlike in the Whitted tracer, albedo times colour is capped at 1 in each channel: a surface
+// can't reflect more light than reaches it, and here the excess would compound at every bounce.
+fn brdf(
+    shading: &ShadingParameters,
+    surface_normal: Vector3,
+    light_direction: Vector3,
+    view_direction: Vector3,
+) -> Color {
+    let reflectance = (shading.color * shading.albedo).clamp();
+    let diffuse = reflectance * std::f32::consts::PI.recip();
+    let exponent = shading.specular_exponent;
+    let halfway = (light_direction + view_direction).normalize();
+    let n_dot_h = (surface_normal.dot(&halfway) as f32).max(0.0);
+    let specular = n_dot_h.powf(exponent) * (exponent + 8.0) / (8.0 * std::f32::consts::PI);
+    diffuse + shading.specular_color * specular
+}
+
+// Weight for a sample taken with density `pdf` when another strategy could have produced it with
+// density `other_pdf`. Veach's power heuristic with an exponent of 2.
+fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    if pdf.is_infinite() {
+        return 1.0;
+    }
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b == 0.0 {
+        0.0
+    } else {
+        a / (a + b)
+    }
+}
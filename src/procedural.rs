@@ -0,0 +1,269 @@
+// Patterns computed on the fly rather than loaded from images: no files to ship, any resolution,
+// and solid patterns that fill space, so they can be evaluated at the hit point itself and wrap
+// around a sphere without seams or stretching.
+//
+// Every pattern gives a value from 0 to 1 at a point, which picks a colour between the two the
+// Procedural is given. The noise based ones are built on Ken Perlin's improved noise
+// (https://mrl.cs.nyu.edu/~perlin/paper445.pdf); Worley noise is from "A Cellular Texture Basis
+// Function" (Worley 1996).
+
+use crate::color::Color;
+use crate::sampling::Rng;
+use crate::texture::{TextureLookup, UvTransform};
+use std::f64::consts::PI;
+use std::sync::OnceLock;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Pattern {
+    Checker, // alternating unit cubes (squares on surfaces)
+    Stripes, // bands half a unit wide, across x
+    // Lines through every whole number along each axis, `line_width` (a fraction of a cell) wide
+    Grid { line_width: f64 },
+    Gradient, // 0 to 1 as x goes from 0 to 1, constant beyond
+    Noise,    // smooth random bumps about a unit across
+    // Noise at `octaves` scales, each half the size and strength of the last. Clouds, rock.
+    Fbm { octaves: u32 },
+    // As Fbm but folding each octave's valleys up into ridges. Billowing smoke, flames.
+    Turbulence { octaves: u32 },
+    Marble, // stripes warped by turbulence
+    Wood,   // rings around the z axis, warped by noise
+    Worley, // distance to the nearest of a scattering of random points: cells, like stones
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum PatternSpace {
+    // Laid on the surface by its texture coords, transformed first, like an image texture.
+    // Patterns are 3D, so this is the slice through z = 0.5, halfway between grid lines.
+    Surface(UvTransform),
+    // Filling space, as if the object were carved from a block of the material. Evaluated at the
+    // hit point times `scale`.
+    Solid { scale: f64 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Procedural {
+    pub pattern: Pattern,
+    pub colors: [Color; 2], // for pattern values 0 and 1, blended in between
+    pub space: PatternSpace,
+}
+
+impl Procedural {
+    pub fn color(&self, lookup: &TextureLookup) -> Color {
+        // The point in the pattern, and about how far apart neighbouring pixels are there
+        let (point, footprint) = match self.space {
+            PatternSpace::Surface(transform) => {
+                let coords = transform.apply(&lookup.coords);
+                let differentials = transform.apply_to_differentials(&lookup.differentials);
+                let (dx, dy) = (differentials.dx, differentials.dy);
+                let footprint = dx.x.hypot(dx.y).max(dy.x.hypot(dy.y));
+                ([coords.x as f64, coords.y as f64, 0.5], footprint as f64)
+            }
+            PatternSpace::Solid { scale } => {
+                let p = lookup.point;
+                (
+                    [p.x * scale, p.y * scale, p.z * scale],
+                    lookup.width * scale,
+                )
+            }
+        };
+        let value = self.pattern.value(point, footprint).clamp(0.0, 1.0) as f32;
+        self.colors[0] * (1.0 - value) + self.colors[1] * value
+    }
+}
+
+impl Pattern {
+    // The patterns with hard edges are averaged over `footprint`, and the noise based ones leave
+    // out details smaller than it, which would only alias
+    fn value(&self, p: [f64; 3], footprint: f64) -> f64 {
+        let [x, y, z] = p;
+        match *self {
+            Pattern::Checker => {
+                let (sx, sy, sz) = (
+                    square_wave(x, footprint),
+                    square_wave(y, footprint),
+                    square_wave(z, footprint),
+                );
+                0.5 - 0.5 * sx * sy * sz
+            }
+            Pattern::Stripes => 0.5 - 0.5 * square_wave(2.0 * x, 2.0 * footprint),
+            Pattern::Grid { line_width } => {
+                let gap = |x| 1.0 - line_coverage(x, line_width, footprint);
+                1.0 - gap(x) * gap(y) * gap(z)
+            }
+            Pattern::Gradient => x,
+            Pattern::Noise => 0.5 + 0.5 * noise(p),
+            Pattern::Fbm { octaves } => 0.5 + 0.5 * fbm(p, octaves, footprint, false),
+            // Turbulence averages about 0.22, so doubled to sit in the middle of the range
+            Pattern::Turbulence { octaves } => 2.0 * fbm(p, octaves, footprint, true),
+            Pattern::Marble => {
+                let turbulence = fbm(p, 6, footprint, true);
+                0.5 + 0.5 * ((x + 4.0 * turbulence) * PI).sin()
+            }
+            Pattern::Wood => {
+                let warp = 0.1 * noise([x * 2.0, y * 2.0, z * 0.25]);
+                let radius = x.hypot(y) + warp;
+                (radius * 4.0).rem_euclid(1.0)
+            }
+            Pattern::Worley => worley(p),
+        }
+    }
+}
+
+// +1 for x in 0..1, -1 for 1..2, and so on, averaged over `width` around x. Integrating the wave
+// gives a triangle wave, whose difference across the width gives the average.
+// https://iquilezles.org/articles/checkerfiltering/
+fn square_wave(x: f64, width: f64) -> f64 {
+    let width = width.max(1e-6);
+    let triangle = |x: f64| ((x * 0.5).rem_euclid(1.0) - 0.5).abs();
+    2.0 * (triangle(x - 0.5 * width) - triangle(x + 0.5 * width)) / width
+}
+
+// Fraction of the `width` around x that's within `line_width` / 2 of a whole number
+fn line_coverage(x: f64, line_width: f64, width: f64) -> f64 {
+    // Running total of line covered from 0 up to x
+    let covered = |x: f64| {
+        let shifted = x + 0.5 * line_width;
+        shifted.floor() * line_width + shifted.rem_euclid(1.0).min(line_width)
+    };
+    if width < 1e-6 {
+        let shifted = (x + 0.5 * line_width).rem_euclid(1.0);
+        return if shifted < line_width { 1.0 } else { 0.0 };
+    }
+    (covered(x + 0.5 * width) - covered(x - 0.5 * width)) / width
+}
+
+// Octaves of noise, each twice the frequency and half the amplitude of the one before. Octaves
+// with details smaller than the footprint fade out into their average.
+fn fbm(p: [f64; 3], octaves: u32, footprint: f64, turbulent: bool) -> f64 {
+    let mut total = 0.0;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.0;
+    for _ in 0..octaves {
+        // Noise features are about a unit across at frequency 1
+        let detail = ((0.5 - frequency * footprint) / 0.25).clamp(0.0, 1.0);
+        if detail == 0.0 && !turbulent {
+            break; // the average of noise is 0, so there's nothing more to add
+        }
+        let n = noise([p[0] * frequency, p[1] * frequency, p[2] * frequency]);
+        let (n, average) = if turbulent {
+            (n.abs(), MEAN_ABS_NOISE)
+        } else {
+            (n, 0.0)
+        };
+        total += amplitude * (n * detail + average * (1.0 - detail));
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    total
+}
+
+// The average of |noise(p)| over space, measured
+const MEAN_ABS_NOISE: f64 = 0.22;
+
+// Perlin's improved noise: smoothly interpolated random gradients at the corners of the unit cube
+// around p. Roughly -1..1, and 0 at every whole numbered point.
+fn noise(p: [f64; 3]) -> f64 {
+    let perm = permutation();
+    let hash = |x: i64, y: i64, z: i64| {
+        let h = perm[(x & 255) as usize] as i64;
+        let h = perm[((h + y) & 255) as usize] as i64;
+        perm[((h + z) & 255) as usize]
+    };
+    let cell = [p[0].floor(), p[1].floor(), p[2].floor()];
+    let (x, y, z) = (p[0] - cell[0], p[1] - cell[1], p[2] - cell[2]);
+    let (i, j, k) = (cell[0] as i64, cell[1] as i64, cell[2] as i64);
+    // 6t^5 - 15t^4 + 10t^3, which has no kinks in its first or second derivatives
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    let lerp = |t: f64, a: f64, b: f64| a + t * (b - a);
+
+    let corner = |di: i64, dj: i64, dk: i64| {
+        gradient(
+            hash(i + di, j + dj, k + dk),
+            x - di as f64,
+            y - dj as f64,
+            z - dk as f64,
+        )
+    };
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+            lerp(u, corner(0, 1, 0), corner(1, 1, 0)),
+        ),
+        lerp(
+            v,
+            lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+            lerp(u, corner(0, 1, 1), corner(1, 1, 1)),
+        ),
+    )
+}
+
+// Dot product of (x, y, z) with one of the 12 vectors from the centre of a cube to its edges,
+// picked by the hash
+fn gradient(hash: u8, x: f64, y: f64, z: f64) -> f64 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+// Distance from p to the nearest feature point, where every unit cube has one at a random spot in
+// it. Only the surrounding cubes can hold a nearer one than p's own.
+fn worley(p: [f64; 3]) -> f64 {
+    let perm = permutation();
+    let cell = [p[0].floor(), p[1].floor(), p[2].floor()];
+    let mut nearest = f64::INFINITY;
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            for dz in -1..=1 {
+                let (x, y, z) = (
+                    cell[0] as i64 + dx,
+                    cell[1] as i64 + dy,
+                    cell[2] as i64 + dz,
+                );
+                let h = perm[(x & 255) as usize] as i64;
+                let h = perm[((h + y) & 255) as usize] as i64;
+                let h = perm[((h + z) & 255) as usize] as usize;
+                // Three different entries of the table for the three coords
+                let offset = |i: usize| (perm[(h + i) & 255] as f64 + 0.5) / 256.0;
+                let feature = [
+                    x as f64 + offset(0),
+                    y as f64 + offset(85),
+                    z as f64 + offset(170),
+                ];
+                let distance = ((feature[0] - p[0]).powi(2)
+                    + (feature[1] - p[1]).powi(2)
+                    + (feature[2] - p[2]).powi(2))
+                .sqrt();
+                nearest = nearest.min(distance);
+            }
+        }
+    }
+    nearest
+}
+
+// 0..255 shuffled, for hashing cell coords. Always shuffled the same way, so the patterns are the
+// same from run to run.
+fn permutation() -> &'static [u8; 256] {
+    static PERMUTATION: OnceLock<[u8; 256]> = OnceLock::new();
+    PERMUTATION.get_or_init(|| {
+        let mut perm = [0u8; 256];
+        for (i, p) in perm.iter_mut().enumerate() {
+            *p = i as u8;
+        }
+        let mut rng = Rng::new(0);
+        for i in (1..perm.len()).rev() {
+            let j = rng.next_u32() as usize % (i + 1);
+            perm.swap(i, j);
+        }
+        perm
+    })
+}
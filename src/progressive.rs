@@ -0,0 +1,91 @@
+// Progressive rendering: rather than taking every sample for a pixel before moving on to the next,
+// render the whole image in passes of a few samples each and average the passes as they come in.
+// There's something to look at after the first pass and it gets less noisy with each one, so slow
+// renders can be previewed, and stopped once they look good enough.
+
+use crate::framebuffer::Framebuffer;
+use crate::scene::Scene;
+use crate::{render_pass, RenderOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// When to stop adding passes. Rendering stops as soon as any of them is met; with none it carries
+// on until cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct StopConditions {
+    pub max_samples: Option<u32>, // per pixel, over all passes
+    // Checked after each pass, so the render can run over by up to one pass
+    pub time_budget: Option<Duration>,
+    // Set from another thread to stop. Checked between scanlines; a pass that's cut short is
+    // thrown away.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+// Where a progressive render has got to, handed to the callback after each pass
+#[derive(Debug)]
+pub struct Progress<'a> {
+    pub image: &'a Framebuffer, // average of the passes so far
+    pub passes: u32,
+    pub samples_per_pixel: u32,
+    pub elapsed: Duration,
+}
+
+// Renders pass after pass, each with `options.samples_per_pixel` samples, until a stop condition is
+// met. `on_pass` sees the image after every pass. Returns the average of all the finished passes
+// (black if none finished). Grid sampling puts the samples in the same places every pass, so
+// jittered or random sampling is needed for passes to refine the image.
+pub fn render_progressive(
+    scene: &Scene,
+    options: &RenderOptions,
+    stop: &StopConditions,
+    mut on_pass: impl FnMut(&Progress),
+) -> Framebuffer {
+    let start = Instant::now();
+    let cancelled = || {
+        stop.cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::Relaxed))
+    };
+    let mut accumulated = Framebuffer::new(scene.width, scene.height);
+    let mut samples = 0;
+    let mut passes = 0;
+    loop {
+        // Cut the last pass short if need be to stop at exactly max_samples
+        let pass_samples = match stop.max_samples {
+            Some(max) => options.samples_per_pixel.min(max.saturating_sub(samples)),
+            None => options.samples_per_pixel,
+        };
+        if pass_samples == 0 || cancelled() {
+            break;
+        }
+        let pass_options = RenderOptions {
+            samples_per_pixel: pass_samples,
+            ..options.clone()
+        };
+        let pass = match render_pass(scene, &pass_options, passes, stop.cancel.as_deref()) {
+            Some(pass) => pass,
+            None => break, // cancelled
+        };
+
+        // Running average, weighted by how many samples each side represents
+        let weight = pass_samples as f32 / (samples + pass_samples) as f32;
+        for (total, new) in accumulated.pixels_mut().iter_mut().zip(pass.pixels()) {
+            *total = *total * (1.0 - weight) + *new * weight;
+        }
+        samples += pass_samples;
+        passes += 1;
+
+        let elapsed = start.elapsed();
+        on_pass(&Progress {
+            image: &accumulated,
+            passes,
+            samples_per_pixel: samples,
+            elapsed,
+        });
+        if stop.time_budget.is_some_and(|budget| elapsed >= budget) {
+            break;
+        }
+    }
+    accumulated
+}
@@ -1,7 +1,15 @@
+use crate::bvh::{Aabb, Bvh};
+use crate::camera::FovAxis;
 use crate::color::Color;
 use crate::point::Point;
-use crate::scene::{Element, Intersection, Plane, Scene, Sphere, SurfaceType};
+use crate::sampling::Rng;
+use crate::scene::{
+    Element, Intersection, Mesh, NormalMap, Plane, Scene, ShadingParameters, Sphere, SurfaceType,
+    Triangle, Vertex,
+};
+use crate::texture::{TextureDifferentials, TextureLookup};
 use crate::vector::Vector3;
+use std::f64::consts::PI;
 
 const BLACK: Color = Color {
     red: 0.0,
@@ -16,46 +24,41 @@ pub struct Ray {
 }
 
 impl Ray {
-    pub fn create_prime(x: u32, y: u32, scene: &Scene) -> Ray {
+    // x and y are screen coordinates, but continuous: pixel (3, 4) covers 3.0..4.0 x 4.0..5.0, so
+    // (3.5, 4.5) is its centre.
+    pub fn create_prime(x: f64, y: f64, scene: &Scene) -> Ray {
         // Recall: pos x is right, pos y is up, pos z is coming out of screen towards us
-        // Camera is at (0, 0, 0)
+        // Work in camera space first: camera is at (0, 0, 0) looking down -z. At the end we map
+        // the direction into world space using the camera's basis vectors.
         // Assume we have a 2x2 unit camera sensor/film plane one unit in front of the camera
         // Coordinates of the sensor will be -1.0..1.0 x -1.0..1.0 (like in OpenGL).
         // screen pixels: 0,0 is in the top left
-        assert!(scene.width > scene.height); // limitation for now
 
         // fov: our working model is that the sensor is 1.0 units in front of the camera. If fov is
         // 90 degrees everything happens to work out. But if fov is, say, 120 degrees we have a
         // problem if the camera is still 1.0 units away from the sensor: some rays in the fov will
         // miss the sensor. With some trig we can adjust the sensor size (keeping it 1.0 units from
         // the camera) to account for this.
-        let fov_adjustment = (scene.fov.to_radians() / 2.0).tan();
+        let fov_adjustment = (scene.camera.fov().to_radians() / 2.0).tan();
 
         // aspect ratio: If we have a square sensor on on camera (as we do: -1.0..1.0 x -1.0..1.0)
         // but a non-square screen, we will have non-square pixels on the sensor, which will cause a
-        // distortion. Multiplying x by aspect ratio will fix this, but also... enlarge? the sensor.
-        // (i.e. 16 aspect-ratio corrected pixels on the sensor have width > 2.0). I think changing
-        // the sensor size like this works ok becuase the sensor coordinates have (0, 0) at the
-        // centre, so it grows equally in all directions. TBD if I really get this, but it is easy
-        // to see what is happening if you draw out dividing a 2.0x2.0 unit sensor into 16x9 pixels.
-        let aspect_ratio = (scene.width as f64) / (scene.height as f64);
-
-        // Map a screen coordinate to sensor space. The +0.5 stuff is because we want rays to pass
-        // through the center of a pixel on the sesor, not the top-left corner. The `1.0-` for the y
-        // coord is because screen pixels have positive y pointing down, but sensor coords have
-        // positive y pointing up.
-        let sensor_x =
-            ((((x as f64 + 0.5) / scene.width as f64) * 2.0 - 1.0) * aspect_ratio) * fov_adjustment;
-        let sensor_y = (1.0 - ((y as f64 + 0.5) / scene.height as f64) * 2.0) * fov_adjustment;
-
+        // distortion. To fix it we stretch the sensor along one or both axes so it has the same
+        // shape as the screen. The fov axis decides which sensor extent stays at -1.0..1.0 (and so
+        // spans exactly the fov); the other axes are scaled relative to it. Since the sensor
+        // coordinates have (0, 0) at the centre it grows/shrinks equally in all directions.
+        let (scale_x, scale_y) = sensor_scale(scene.width, scene.height, scene.camera.fov_axis());
+
+        // Map a screen coordinate to sensor space. The `1.0-` for the y coord is because screen
+        // pixels have positive y pointing down, but sensor coords have positive y pointing up.
+        let sensor_x = (((x / scene.width as f64) * 2.0 - 1.0) * scale_x) * fov_adjustment;
+        let sensor_y = ((1.0 - (y / scene.height as f64) * 2.0) * scale_y) * fov_adjustment;
+
+        // Camera space (sensor_x, sensor_y, -1.0) to world space
+        let (right, up, forward) = scene.camera.basis();
         Ray {
-            origin: Point::zero(),
-            direction: Vector3 {
-                x: sensor_x,
-                y: sensor_y,
-                z: -1.0,
-            }
-            .normalize(),
+            origin: scene.camera.position(),
+            direction: (right * sensor_x + up * sensor_y + forward).normalize(),
         }
     }
 
@@ -70,23 +73,183 @@ impl Ray {
             direction: incident - (2.0 * incident.dot(&normal) * normal),
         }
     }
+
+    // Bends the incident ray through the surface according to Snell's law. `index` is the
+    // refractive index of the element's material; the other side is assumed to be air (1.0).
+    // Returns None when there's total internal reflection.
+    pub fn create_transmission(
+        normal: Vector3,
+        incident: Vector3,
+        intersection: Point,
+        bias: f64,
+        index: f32,
+    ) -> Option<Ray> {
+        let mut ref_n = normal;
+        let mut eta_t = index as f64;
+        let mut eta_i = 1.0f64;
+        let mut i_dot_n = incident.dot(&normal);
+        if i_dot_n < 0.0 {
+            // Outside the surface
+            i_dot_n = -i_dot_n;
+        } else {
+            // Inside the surface; invert the normal and swap the indices of refraction
+            ref_n = -normal;
+            eta_t = 1.0;
+            eta_i = index as f64;
+        }
+
+        let eta = eta_i / eta_t;
+        let k = 1.0 - (eta * eta) * (1.0 - i_dot_n * i_dot_n);
+        if k < 0.0 {
+            None
+        } else {
+            Some(Ray {
+                // start just past the surface, on the far side from where we came in
+                origin: intersection + (ref_n * -bias),
+                direction: ((incident + i_dot_n * ref_n) * eta - ref_n * k.sqrt()).normalize(),
+            })
+        }
+    }
 }
 
-#[derive(Debug)]
+// Each ray really stands for a narrow cone of them, about as wide as the gap between neighbouring
+// pixels (or samples) wherever it gets to. Texture filtering uses the cone's width at a hit to work
+// out how much of the texture a pixel covers there. Camera rays start as a point and spread by the
+// angle between pixels; reflected and refracted rays carry on spreading at the same rate from
+// wherever the cone had got to. Curved mirrors and lenses would really change the spread, but
+// this only decides how blurry textures are, so near enough is good enough. See Akenine-Möller et
+// al., "Texture Level of Detail Strategies for Real-Time Ray Tracing".
+#[derive(Copy, Clone, Debug)]
+pub struct RayCone {
+    pub width: f64,
+    pub spread: f64, // increase in width per unit of distance travelled
+}
+
+impl RayCone {
+    // Cone for camera rays. With several samples per pixel each stands for a smaller area.
+    pub fn primary(scene: &Scene, samples_per_pixel: u32) -> RayCone {
+        // Width of a pixel on the sensor in create_prime, which is one unit from the camera
+        let (scale_x, _) = sensor_scale(scene.width, scene.height, scene.camera.fov_axis());
+        let fov_adjustment = (scene.camera.fov().to_radians() / 2.0).tan();
+        let pixel_width = 2.0 * scale_x * fov_adjustment / scene.width as f64;
+        RayCone {
+            width: 0.0,
+            spread: pixel_width / (samples_per_pixel.max(1) as f64).sqrt(),
+        }
+    }
+
+    // The cone for a ray leaving a hit `distance` along this one
+    pub fn after(&self, distance: f64) -> RayCone {
+        RayCone {
+            width: self.width + self.spread * distance,
+            spread: self.spread,
+        }
+    }
+}
+
+// Fraction of light reflected (rather than transmitted) at a surface with the given refractive
+// index, from the Fresnel equations. 1.0 means total internal reflection.
+pub fn fresnel(incident: Vector3, normal: Vector3, index: f32) -> f64 {
+    let i_dot_n = incident.dot(&normal);
+    let mut eta_i = 1.0;
+    let mut eta_t = index as f64;
+    if i_dot_n > 0.0 {
+        eta_i = eta_t;
+        eta_t = 1.0;
+    }
+
+    let sin_t = eta_i / eta_t * (1.0 - i_dot_n * i_dot_n).max(0.0).sqrt();
+    if sin_t > 1.0 {
+        1.0 // total internal reflection
+    } else {
+        let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
+        let cos_i = i_dot_n.abs();
+        let r_s = ((eta_t * cos_i) - (eta_i * cos_t)) / ((eta_t * cos_i) + (eta_i * cos_t));
+        let r_p = ((eta_i * cos_i) - (eta_t * cos_t)) / ((eta_i * cos_i) + (eta_t * cos_t));
+        (r_s * r_s + r_p * r_p) / 2.0
+    }
+}
+
+// Returns (x, y) multipliers that give the sensor the same aspect ratio as the screen, keeping the
+// extent along the fov axis at -1.0..1.0.
+fn sensor_scale(width: u32, height: u32, fov_axis: FovAxis) -> (f64, f64) {
+    let (width, height) = (width as f64, height as f64);
+    match fov_axis {
+        FovAxis::Horizontal => (1.0, height / width),
+        FovAxis::Vertical => (width / height, 1.0),
+        FovAxis::Diagonal => {
+            let diagonal = (width * width + height * height).sqrt();
+            (width / diagonal, height / diagonal)
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
 pub struct TextureCoords {
     pub x: f32,
     pub y: f32,
 }
 
+#[derive(Copy, Clone, Debug)]
+pub struct Hit {
+    pub distance: f64,
+    // Which part of the element was hit, e.g. the face index for a mesh. Always 0 for elements
+    // that are a single primitive.
+    pub primitive: usize,
+}
+
+impl Hit {
+    fn new(distance: f64) -> Hit {
+        Hit {
+            distance,
+            primitive: 0,
+        }
+    }
+}
+
 pub trait Intersectable {
     // Returns distance from camera origin to point of intersection (if there is one)
-    fn intersect(&self, ray: &Ray) -> Option<f64>;
-    fn surface_normal(&self, hit_point: &Point) -> Vector3;
-    fn texture_coords(&self, hit_point: &Point) -> TextureCoords;
+    fn intersect(&self, ray: &Ray) -> Option<Hit>;
+    fn surface_normal(&self, hit_point: &Point, primitive: usize) -> Vector3;
+    fn texture_coords(&self, hit_point: &Point, primitive: usize) -> TextureCoords;
+    // How the hit point moves as each of the texture coords increases, i.e. the derivatives of
+    // position with respect to x and y. These orient normal and bump maps on the surface.
+    fn texture_tangents(&self, hit_point: &Point, primitive: usize) -> (Vector3, Vector3);
+    // None for things that go on forever, like planes
+    fn bounding_box(&self) -> Option<Aabb>;
+
+    // How much the texture coords change moving from the hit point by each of the offsets, which
+    // lie in the surface's tangent plane
+    fn texture_differentials(
+        &self,
+        hit_point: &Point,
+        primitive: usize,
+        offsets: [Vector3; 2],
+    ) -> TextureDifferentials {
+        let coords = self.texture_coords(hit_point, primitive);
+        let difference = |offset: Vector3| {
+            let moved = self.texture_coords(&(*hit_point + offset), primitive);
+            TextureCoords {
+                x: moved.x - coords.x,
+                y: moved.y - coords.y,
+            }
+        };
+        TextureDifferentials {
+            dx: difference(offsets[0]),
+            dy: difference(offsets[1]),
+        }
+    }
+
+    // Whether the ray hits this closer than max_distance. Elements made of many primitives can
+    // answer this without finding the nearest hit.
+    fn occludes(&self, ray: &Ray, max_distance: f64) -> bool {
+        self.intersect(ray)
+            .is_some_and(|hit| hit.distance < max_distance)
+    }
 }
 
 impl Intersectable for Sphere {
-    fn intersect(&self, ray: &Ray) -> Option<f64> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
         let vec_to_center: Vector3 = self.center - ray.origin;
         let adj: f64 = vec_to_center.dot(&ray.direction);
         let hyp2 = vec_to_center.dot(&vec_to_center); // len(v) == v.dot(v).sqrt()
@@ -105,43 +268,132 @@ impl Intersectable for Sphere {
         if t0 < 0.0 && t1 < 0.0 {
             return None;
         }
-        let distance = if t0 < t1 { t0 } else { t1 };
-        Some(distance)
+        // t0 is always the nearer one, but if the ray starts inside the sphere it's behind us and
+        // the hit we want is on the way out
+        let distance = if t0 < 0.0 { t1 } else { t0 };
+        Some(Hit::new(distance))
     }
 
-    fn surface_normal(&self, hit_point: &Point) -> Vector3 {
+    fn surface_normal(&self, hit_point: &Point, _: usize) -> Vector3 {
         (*hit_point - self.center).normalize()
     }
 
-    fn texture_coords(&self, hit_point: &Point) -> TextureCoords {
+    fn texture_coords(&self, hit_point: &Point, _: usize) -> TextureCoords {
         let hit_vec = *hit_point - self.center; // vector from center to point of intersection
         TextureCoords {
             x: (1.0 + (hit_vec.z.atan2(hit_vec.x) as f32) / std::f32::consts::PI) * 0.5,
             y: (hit_vec.y / self.radius).acos() as f32 / std::f32::consts::PI,
         }
     }
+
+    fn texture_differentials(
+        &self,
+        hit_point: &Point,
+        _: usize,
+        offsets: [Vector3; 2],
+    ) -> TextureDifferentials {
+        // The offset points are off the sphere, so pull them back onto it first. The x coord
+        // wraps around from 1 to 0 at the seam, where the short way round is the right difference.
+        let coords = self.texture_coords(hit_point, 0);
+        let difference = |offset: Vector3| {
+            let on_sphere =
+                self.center + (*hit_point + offset - self.center).normalize() * self.radius;
+            let moved = self.texture_coords(&on_sphere, 0);
+            let dx = moved.x - coords.x;
+            TextureCoords {
+                x: dx - dx.round(),
+                y: moved.y - coords.y,
+            }
+        };
+        TextureDifferentials {
+            dx: difference(offsets[0]),
+            dy: difference(offsets[1]),
+        }
+    }
+
+    fn texture_tangents(&self, hit_point: &Point, _: usize) -> (Vector3, Vector3) {
+        // x is the longitude and y the angle down from the north pole, both scaled to 0..1
+        let h = *hit_point - self.center;
+        let around = h.x.hypot(h.z); // distance from the axis
+        if around < 1e-9 * self.radius {
+            // At the poles every direction is south
+            return self.surface_normal(hit_point).orthonormal_basis();
+        }
+        let east = Vector3 {
+            x: -h.z,
+            y: 0.0,
+            z: h.x,
+        };
+        let south = Vector3 {
+            x: h.y * h.x / around,
+            y: -around,
+            z: h.y * h.z / around,
+        };
+        (east * (2.0 * PI), south * PI)
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        let r = Vector3 {
+            x: self.radius,
+            y: self.radius,
+            z: self.radius,
+        };
+        Some(Aabb {
+            min: self.center + -r,
+            max: self.center + r,
+        })
+    }
 }
 
 impl Intersectable for Plane {
-    fn intersect(&self, ray: &Ray) -> Option<f64> {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
         let normal = &self.normal;
         let denom = normal.dot(&ray.direction);
         if denom > 1e-6 {
             // really close to zero == zero for us
             let v = self.origin - ray.origin;
-            let distance = v.dot(&normal) / denom;
+            let distance = v.dot(normal) / denom;
             if distance >= 0.0 {
-                return Some(distance);
+                return Some(Hit::new(distance));
             }
         }
         None
     }
 
-    fn surface_normal(&self, _: &Point) -> Vector3 {
+    fn surface_normal(&self, _: &Point, _: usize) -> Vector3 {
         -self.normal
     }
 
-    fn texture_coords(&self, hit_point: &Point) -> TextureCoords {
+    fn texture_coords(&self, hit_point: &Point, _: usize) -> TextureCoords {
+        let (x_axis, y_axis) = self.texture_axes();
+
+        // Now we need to map the hit point to our new x and y axes. Do this by projecting the hit
+        // vector onto each of our axes.
+        let hit_vec = *hit_point - self.origin;
+
+        TextureCoords {
+            x: hit_vec.dot(&x_axis) as f32,
+            y: hit_vec.dot(&y_axis) as f32,
+        }
+    }
+
+    fn texture_tangents(&self, _: &Point, _: usize) -> (Vector3, Vector3) {
+        // The axes are perpendicular but not necessarily unit length; moving along one by d
+        // changes the coord by d * its length
+        let (x_axis, y_axis) = self.texture_axes();
+        (
+            x_axis * x_axis.dot(&x_axis).recip(),
+            y_axis * y_axis.dot(&y_axis).recip(),
+        )
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        None
+    }
+}
+
+impl Plane {
+    fn texture_axes(&self) -> (Vector3, Vector3) {
         // We need basis vectors for the plane. We'll get our x axis by crossing the surface normal
         // and the forward vector. If the surface normal happens to BE the forward vector, we'll
         // cross the normal with the up vector). This gives us a vector in our plane to be our x
@@ -159,101 +411,587 @@ impl Intersectable for Plane {
             });
         }
         let y_axis = self.normal.cross(&x_axis);
+        (x_axis, y_axis)
+    }
+}
 
-        // Now we need to map the hit point to our new x and y axes. Do this by projecting the hit
-        // vector onto each of our axes.
-        let hit_vec = *hit_point - self.origin;
+// Möller-Trumbore ray/triangle intersection. Triangles are two sided.
+fn intersect_triangle(ray: &Ray, vertices: [&Vertex; 3]) -> Option<f64> {
+    let edge1 = vertices[1].position - vertices[0].position;
+    let edge2 = vertices[2].position - vertices[0].position;
+    let p = ray.direction.cross(&edge2);
+    let det = edge1.dot(&p);
+    if det.abs() < 1e-12 {
+        return None; // ray is parallel to the triangle
+    }
+    let inv_det = det.recip();
+    let t = ray.origin - vertices[0].position;
+    let u = t.dot(&p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = t.cross(&edge1);
+    let v = ray.direction.dot(&q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let distance = edge2.dot(&q) * inv_det;
+    if distance > 0.0 {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+// Weights of each vertex for a point in (or near) the plane of the triangle. They sum to 1.0.
+fn barycentric(hit_point: &Point, vertices: [&Vertex; 3]) -> [f64; 3] {
+    let edge1 = vertices[1].position - vertices[0].position;
+    let edge2 = vertices[2].position - vertices[0].position;
+    let to_hit = *hit_point - vertices[0].position;
+    let d11 = edge1.dot(&edge1);
+    let d12 = edge1.dot(&edge2);
+    let d22 = edge2.dot(&edge2);
+    let dh1 = to_hit.dot(&edge1);
+    let dh2 = to_hit.dot(&edge2);
+    let denom = d11 * d22 - d12 * d12;
+    let v = (d22 * dh1 - d12 * dh2) / denom;
+    let w = (d11 * dh2 - d12 * dh1) / denom;
+    [1.0 - v - w, v, w]
+}
 
+// Interpolates the vertex normals if every vertex has one, otherwise uses the face normal. The
+// front face is the one the vertices wind counter-clockwise around.
+fn triangle_normal(hit_point: &Point, vertices: [&Vertex; 3]) -> Vector3 {
+    if let [Some(n0), Some(n1), Some(n2)] =
+        [vertices[0].normal, vertices[1].normal, vertices[2].normal]
+    {
+        let [w0, w1, w2] = barycentric(hit_point, vertices);
+        (n0 * w0 + n1 * w1 + n2 * w2).normalize()
+    } else {
+        let edge1 = vertices[1].position - vertices[0].position;
+        let edge2 = vertices[2].position - vertices[0].position;
+        edge1.cross(&edge2).normalize()
+    }
+}
+
+// Interpolates the vertex texture coords if every vertex has them. Otherwise falls back to the
+// barycentric coords, which at least stretches the texture over the triangle.
+fn triangle_texture_coords(hit_point: &Point, vertices: [&Vertex; 3]) -> TextureCoords {
+    let [w0, w1, w2] = barycentric(hit_point, vertices);
+    if let [Some(t0), Some(t1), Some(t2)] = [
+        vertices[0].texture_coords,
+        vertices[1].texture_coords,
+        vertices[2].texture_coords,
+    ] {
+        let (w0, w1, w2) = (w0 as f32, w1 as f32, w2 as f32);
         TextureCoords {
-            x: hit_vec.dot(&x_axis) as f32,
-            y: hit_vec.dot(&y_axis) as f32,
+            x: t0.x * w0 + t1.x * w1 + t2.x * w2,
+            y: t0.y * w0 + t1.y * w1 + t2.y * w2,
+        }
+    } else {
+        TextureCoords {
+            x: w1 as f32,
+            y: w2 as f32,
+        }
+    }
+}
+
+// Solves for the derivatives from how the texture coords change along the edges. Without texture
+// coords (or with degenerate ones) the barycentric coords stand in, as in triangle_texture_coords.
+fn triangle_texture_tangents(vertices: [&Vertex; 3]) -> (Vector3, Vector3) {
+    let edge1 = vertices[1].position - vertices[0].position;
+    let edge2 = vertices[2].position - vertices[0].position;
+    if let [Some(t0), Some(t1), Some(t2)] = [
+        vertices[0].texture_coords,
+        vertices[1].texture_coords,
+        vertices[2].texture_coords,
+    ] {
+        let (du1, dv1) = ((t1.x - t0.x) as f64, (t1.y - t0.y) as f64);
+        let (du2, dv2) = ((t2.x - t0.x) as f64, (t2.y - t0.y) as f64);
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() > 1e-12 {
+            return (
+                (edge1 * dv2 - edge2 * dv1) * det.recip(),
+                (edge2 * du1 - edge1 * du2) * det.recip(),
+            );
+        }
+    }
+    (edge1, edge2)
+}
+
+fn triangle_bounds(vertices: [&Vertex; 3]) -> Aabb {
+    Aabb::from_points(&[
+        vertices[0].position,
+        vertices[1].position,
+        vertices[2].position,
+    ])
+}
+
+impl Triangle {
+    fn vertex_refs(&self) -> [&Vertex; 3] {
+        [&self.vertices[0], &self.vertices[1], &self.vertices[2]]
+    }
+}
+
+impl Intersectable for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        intersect_triangle(ray, self.vertex_refs()).map(Hit::new)
+    }
+
+    fn surface_normal(&self, hit_point: &Point, _: usize) -> Vector3 {
+        triangle_normal(hit_point, self.vertex_refs())
+    }
+
+    fn texture_coords(&self, hit_point: &Point, _: usize) -> TextureCoords {
+        triangle_texture_coords(hit_point, self.vertex_refs())
+    }
+
+    fn texture_tangents(&self, _: &Point, _: usize) -> (Vector3, Vector3) {
+        triangle_texture_tangents(self.vertex_refs())
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some(triangle_bounds(self.vertex_refs()))
+    }
+}
+
+impl Mesh {
+    fn face_vertices(&self, face: usize) -> [&Vertex; 3] {
+        let [a, b, c] = self.faces[face];
+        [&self.vertices[a], &self.vertices[b], &self.vertices[c]]
+    }
+
+    // Builds the hierarchy over the faces that intersect uses. Needs redoing if the faces change.
+    pub fn prepare(&mut self) {
+        let bounds: Vec<Option<Aabb>> = (0..self.faces.len())
+            .map(|face| Some(triangle_bounds(self.face_vertices(face))))
+            .collect();
+        self.bvh = Some(Bvh::build(&bounds));
+    }
+
+    fn intersect_face(&self, ray: &Ray, face: usize) -> Option<Hit> {
+        intersect_triangle(ray, self.face_vertices(face)).map(|distance| Hit {
+            distance,
+            primitive: face,
+        })
+    }
+}
+
+impl Intersectable for Mesh {
+    // The primitive in the returned Hit is the index of the face that was hit
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        match self.bvh {
+            Some(ref bvh) => bvh
+                .intersect(ray, |face| self.intersect_face(ray, face))
+                .map(|(_, hit)| hit),
+            None => (0..self.faces.len())
+                .filter_map(|face| self.intersect_face(ray, face))
+                .min_by(|h1, h2| h1.distance.partial_cmp(&h2.distance).unwrap()),
+        }
+    }
+
+    fn surface_normal(&self, hit_point: &Point, face: usize) -> Vector3 {
+        triangle_normal(hit_point, self.face_vertices(face))
+    }
+
+    fn texture_coords(&self, hit_point: &Point, face: usize) -> TextureCoords {
+        triangle_texture_coords(hit_point, self.face_vertices(face))
+    }
+
+    fn texture_tangents(&self, _: &Point, face: usize) -> (Vector3, Vector3) {
+        triangle_texture_tangents(self.face_vertices(face))
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        Some((0..self.faces.len()).fold(Aabb::empty(), |b, face| {
+            b.union(&triangle_bounds(self.face_vertices(face)))
+        }))
+    }
+
+    fn occludes(&self, ray: &Ray, max_distance: f64) -> bool {
+        let blocks = |face| {
+            self.intersect_face(ray, face)
+                .is_some_and(|hit| hit.distance < max_distance)
+        };
+        match self.bvh {
+            Some(ref bvh) => bvh.occluded(ray, max_distance, blocks),
+            None => (0..self.faces.len()).any(blocks),
         }
     }
 }
 
 impl Intersectable for Element {
-    fn intersect(&self, ray: &Ray) -> Option<f64> {
-        match *self {
+    fn intersect(&self, ray: &Ray) -> Option<Hit> {
+        let hit = match *self {
             Element::Sphere(ref s) => s.intersect(ray),
             Element::Plane(ref p) => p.intersect(ray),
-        }
+            Element::Triangle(ref t) => t.intersect(ray),
+            Element::Mesh(ref m) => m.intersect(ray),
+        };
+        // Geometry near the limits of f64 can overflow to an infinite or NaN distance, which
+        // isn't anywhere along the ray
+        hit.filter(|hit| hit.distance.is_finite())
     }
 
-    fn surface_normal(&self, p: &Point) -> Vector3 {
+    fn surface_normal(&self, p: &Point, primitive: usize) -> Vector3 {
         match *self {
             Element::Sphere(ref sphere) => sphere.surface_normal(p),
-            Element::Plane(ref plane) => plane.surface_normal(p),
+            Element::Plane(ref plane) => plane.surface_normal(p, primitive),
+            Element::Triangle(ref triangle) => triangle.surface_normal(p, primitive),
+            Element::Mesh(ref mesh) => mesh.surface_normal(p, primitive),
         }
     }
 
-    fn texture_coords(&self, hit_point: &Point) -> TextureCoords {
+    fn texture_coords(&self, hit_point: &Point, primitive: usize) -> TextureCoords {
         match self {
-            Element::Sphere(ref s) => s.texture_coords(hit_point),
-            Element::Plane(ref p) => p.texture_coords(hit_point),
+            Element::Sphere(ref s) => s.texture_coords(hit_point, primitive),
+            Element::Plane(ref p) => p.texture_coords(hit_point, primitive),
+            Element::Triangle(ref t) => t.texture_coords(hit_point, primitive),
+            Element::Mesh(ref m) => m.texture_coords(hit_point, primitive),
+        }
+    }
+
+    fn texture_tangents(&self, hit_point: &Point, primitive: usize) -> (Vector3, Vector3) {
+        match self {
+            Element::Sphere(ref s) => s.texture_tangents(hit_point, primitive),
+            Element::Plane(ref p) => p.texture_tangents(hit_point, primitive),
+            Element::Triangle(ref t) => t.texture_tangents(hit_point, primitive),
+            Element::Mesh(ref m) => m.texture_tangents(hit_point, primitive),
+        }
+    }
+
+    fn texture_differentials(
+        &self,
+        hit_point: &Point,
+        primitive: usize,
+        offsets: [Vector3; 2],
+    ) -> TextureDifferentials {
+        match self {
+            Element::Sphere(ref s) => s.texture_differentials(hit_point, primitive, offsets),
+            Element::Plane(ref p) => p.texture_differentials(hit_point, primitive, offsets),
+            Element::Triangle(ref t) => t.texture_differentials(hit_point, primitive, offsets),
+            Element::Mesh(ref m) => m.texture_differentials(hit_point, primitive, offsets),
+        }
+    }
+
+    fn bounding_box(&self) -> Option<Aabb> {
+        match self {
+            Element::Sphere(ref s) => s.bounding_box(),
+            Element::Plane(ref p) => p.bounding_box(),
+            Element::Triangle(ref t) => t.bounding_box(),
+            Element::Mesh(ref m) => m.bounding_box(),
+        }
+    }
+
+    fn occludes(&self, ray: &Ray, max_distance: f64) -> bool {
+        match self {
+            Element::Mesh(ref m) => m.occludes(ray, max_distance),
+            _ => self
+                .intersect(ray)
+                .is_some_and(|hit| hit.distance < max_distance),
         }
     }
 }
 
-pub fn shade_diffuse(
+// Direct lighting from each light: a Lambertian diffuse term plus a Blinn-Phong specular
+// highlight, both skipped where a shadow ray finds something between the point and the light
+// (anything beyond a point light doesn't count).
+// `view_direction` points from the hit point back towards whoever is looking at it.
+pub fn shade(
     scene: &Scene,
-    element: &Element,
+    shading: &ShadingParameters,
     hit_point: Point,
     surface_normal: Vector3,
+    view_direction: Vector3,
+    rng: &mut Rng,
 ) -> Color {
-    let texture_coords = element.texture_coords(&hit_point);
-
     let mut color = Color {
         red: 0.0,
         green: 0.0,
         blue: 0.0,
     };
 
+    let light_reflected = shading.albedo / std::f32::consts::PI;
+    let exponent = shading.specular_exponent;
+    let specular_normalization = (exponent + 8.0) / (8.0 * std::f32::consts::PI);
+
     for light in &scene.lights {
-        let direction_to_light = light.direction_from(&hit_point); //.normalize();
-        let shadow_ray = Ray {
-            origin: hit_point + (surface_normal * scene.shadow_bias),
-            direction: direction_to_light,
-        };
-        let in_light: bool = scene.trace(&shadow_ray).is_none();
+        // Lights with area are sampled at several points and the results averaged, which is what
+        // softens the edges of their shadows
+        let samples = light.sample_count();
+        let sample_weight = (samples as f32).recip();
+        for _ in 0..samples {
+            let sample = light.sample(&hit_point, rng);
+            let cos_theta = surface_normal.dot(&sample.direction) as f32;
+            if cos_theta <= 0.0 {
+                continue; // light is behind the surface
+            }
+            let shadow_ray = Ray {
+                origin: hit_point + (surface_normal * scene.shadow_bias),
+                direction: sample.direction,
+            };
+            if scene.occluded(&shadow_ray, sample.distance) {
+                continue;
+            }
+
+            let light_power = cos_theta * sample.intensity * sample_weight;
+            let light_color = light.color() * light_power * light_reflected;
+            color = color + (shading.color * light_color);
+
+            // Blinn-Phong: the highlight is brightest where the normal lines up with the vector
+            // halfway between the light and the viewer. The (n + 8) / 8pi factor keeps the total
+            // reflected light about the same as the exponent changes, so sharper highlights are
+            // brighter rather than just smaller.
+            let halfway = (sample.direction + view_direction).normalize();
+            let n_dot_h = (surface_normal.dot(&halfway) as f32).max(0.0);
+            let specular = n_dot_h.powf(exponent) * specular_normalization * light_power;
+            color = color + (shading.specular_color * light.color() * specular);
+        }
+    }
+
+    color
+}
 
-        let light_intensity = if in_light {
-            light.intensity(&hit_point)
+// Where to look up the material's textures and patterns for the point the ray hit, with the area
+// the ray's cone covers there so they can be filtered over it
+pub fn texture_lookup(
+    ray: &Ray,
+    cone: &RayCone,
+    intersection: &Intersection,
+    hit_point: Point,
+    surface_normal: Vector3,
+) -> TextureLookup {
+    let element = intersection.element;
+    let coords = element.texture_coords(&hit_point, intersection.primitive);
+    let width = cone.after(intersection.distance).width;
+    let differentials = if width > 0.0 {
+        // Offsets across the cone: one in the plane of the ray and the normal, which the surface
+        // stretches out the more glancing the angle, and one perpendicular to that, which it
+        // doesn't. Both are slid along the ray onto the surface's tangent plane.
+        let direction = ray.direction;
+        let d_dot_n = direction.dot(&surface_normal);
+        let across = surface_normal - direction * d_dot_n;
+        let (stretched, unstretched) = if across.length() > 1e-9 {
+            let across = across.normalize();
+            (across, direction.cross(&across))
         } else {
-            0.0
+            direction.orthonormal_basis()
         };
-        let light_power: f32 =
-            (surface_normal.dot(&direction_to_light) as f32).max(0.0) * light_intensity;
-        let light_reflected = element.material().albedo / std::f32::consts::PI;
-
-        let light_color = light.color() * light_power * light_reflected;
-        color = color + (element.material().coloration.color(&texture_coords) * light_color);
-        // println!("{:?}", color);
+        // Keep the stretch finite for rays that just graze the surface
+        let d_dot_n = if d_dot_n.abs() < 1e-3 {
+            1e-3f64.copysign(d_dot_n)
+        } else {
+            d_dot_n
+        };
+        let onto_surface =
+            |offset: Vector3| offset - direction * (offset.dot(&surface_normal) / d_dot_n);
+        element.texture_differentials(
+            &hit_point,
+            intersection.primitive,
+            [
+                onto_surface(stretched * width),
+                onto_surface(unstretched * width),
+            ],
+        )
+    } else {
+        TextureDifferentials::zero()
+    };
+    TextureLookup {
+        point: hit_point,
+        coords,
+        differentials,
+        width,
     }
+}
 
-    color.clamp()
+// The normal to shade with: the surface's own, tilted by the material's normal or bump map if it
+// has one
+pub fn shading_normal(
+    intersection: &Intersection,
+    lookup: &TextureLookup,
+    surface_normal: Vector3,
+) -> Vector3 {
+    let element = intersection.element;
+    let normal_map = match element.material().normal_map {
+        Some(ref normal_map) => normal_map,
+        None => return surface_normal,
+    };
+    let (along_x, along_y) = element.texture_tangents(&lookup.point, intersection.primitive);
+    let perturbed = match *normal_map {
+        NormalMap::Normals(ref texture) => {
+            // The map's own scale and rotation turn the frame too; undo them to find how the
+            // position moves along the image's axes
+            let axes = texture
+                .transform
+                .apply_to_differentials(&TextureDifferentials {
+                    dx: TextureCoords { x: 1.0, y: 0.0 },
+                    dy: TextureCoords { x: 0.0, y: 1.0 },
+                });
+            let (a, b, c, d) = (
+                axes.dx.x as f64,
+                axes.dy.x as f64,
+                axes.dx.y as f64,
+                axes.dy.y as f64,
+            );
+            let det = a * d - b * c;
+            if det.abs() < 1e-12 {
+                return surface_normal;
+            }
+            let image_x = (along_x * d - along_y * c) * det.recip();
+            let image_y = (along_y * a - along_x * b) * det.recip();
+
+            // Orthonormal frame with the normal, keeping the map's up as up even where the
+            // texture coords are mirrored
+            let tangent = (image_x - surface_normal * image_x.dot(&surface_normal)).normalize();
+            let up = -image_y;
+            let up = (up - surface_normal * up.dot(&surface_normal) - tangent * up.dot(&tangent))
+                .normalize();
+            if !tangent.x.is_finite() || !up.x.is_finite() {
+                return surface_normal;
+            }
+            let encoded = texture.sample(&lookup.coords, &lookup.differentials);
+            let component = |c: f32| (c * 2.0 - 1.0) as f64;
+            tangent * component(encoded.red)
+                + up * component(encoded.green)
+                + surface_normal * component(encoded.blue)
+        }
+        NormalMap::Bump { ref height, scale } => {
+            // Slopes of the height field by finite differences, a step about the size of the
+            // footprint so they're filtered like the colour
+            let d = &lookup.differentials;
+            let step = |a: f32, b: f32| {
+                let step = 0.5 * (a.abs() + b.abs());
+                if step > 0.0 {
+                    step
+                } else {
+                    0.0005
+                }
+            };
+            let (du, dv) = (step(d.dx.x, d.dy.x), step(d.dx.y, d.dy.y));
+            let height_at = |du: f32, dv: f32| {
+                let shifted = TextureLookup {
+                    point: lookup.point + along_x * du as f64 + along_y * dv as f64,
+                    coords: TextureCoords {
+                        x: lookup.coords.x + du,
+                        y: lookup.coords.y + dv,
+                    },
+                    ..*lookup
+                };
+                (height.color(&shifted).luminance() * scale) as f64
+            };
+            let h = height_at(0.0, 0.0);
+            let slope_x = (height_at(du, 0.0) - h) / du as f64;
+            let slope_y = (height_at(0.0, dv) - h) / dv as f64;
+            // The displaced surface's tangents, ignoring how the normal itself turns
+            let bumped =
+                (along_x + surface_normal * slope_x).cross(&(along_y + surface_normal * slope_y));
+            if bumped.dot(&surface_normal) < 0.0 {
+                -bumped
+            } else {
+                bumped
+            }
+        }
+    };
+    let perturbed = perturbed.normalize();
+    if perturbed.x.is_finite() && perturbed.dot(&surface_normal) > 0.0 {
+        perturbed
+    } else {
+        surface_normal
+    }
 }
 
-pub fn get_color(scene: &Scene, ray: &Ray, intersection: &Intersection, depth: u32) -> Color {
+pub fn get_color(
+    scene: &Scene,
+    ray: &Ray,
+    cone: &RayCone,
+    intersection: &Intersection,
+    depth: u32,
+    rng: &mut Rng,
+) -> Color {
     let hit_point = ray.origin + (ray.direction * intersection.distance);
-    let surface_normal = intersection.element.surface_normal(&hit_point);
+    let surface_normal = intersection
+        .element
+        .surface_normal(&hit_point, intersection.primitive);
+
+    let material = intersection.element.material();
+    let lookup = texture_lookup(ray, cone, intersection, hit_point, surface_normal);
+    let shading = material.shading_parameters(&lookup);
+    let surface_normal = shading_normal(intersection, &lookup, surface_normal);
+    let next_cone = cone.after(intersection.distance);
+    // The side of the surface the ray arrived on. Triangles can be seen from behind, and rays
+    // inside glass meet its surfaces from within; either way that's the side that gets lit and
+    // that shadow and reflection rays leave from.
+    let facing_normal = if ray.direction.dot(&surface_normal) > 0.0 {
+        -surface_normal
+    } else {
+        surface_normal
+    };
+    let mut color = shade(
+        scene,
+        &shading,
+        hit_point,
+        facing_normal,
+        -ray.direction,
+        rng,
+    );
+    match material.surface {
+        SurfaceType::Diffuse => {}
+        SurfaceType::Reflective { ref reflectivity } => {
+            // Where a map masks the reflection off there's no need to trace it
+            let reflectivity = reflectivity.value(&lookup);
+            if reflectivity > 0.0 {
+                let reflection_ray = Ray::create_reflection(
+                    facing_normal,
+                    ray.direction,
+                    hit_point,
+                    scene.shadow_bias,
+                );
+                color = color * (1.0 - reflectivity);
+                color = color
+                    + (cast_ray(scene, &reflection_ray, &next_cone, depth + 1, rng) * reflectivity);
+            }
+        }
+        SurfaceType::Refractive {
+            ref index,
+            ref transparency,
+        } => {
+            let index = index.value(&lookup);
+            let transparency = transparency.value(&lookup);
+            // Split the light between a reflected and a transmitted ray by the Fresnel terms,
+            // tinted by the surface colour, then mix that with the diffuse shading by transparency
+            let kr = fresnel(ray.direction, surface_normal, index) as f32;
+            let mut refraction_color = BLACK;
+            if kr < 1.0 {
+                if let Some(transmission_ray) = Ray::create_transmission(
+                    surface_normal,
+                    ray.direction,
+                    hit_point,
+                    scene.shadow_bias,
+                    index,
+                ) {
+                    refraction_color =
+                        cast_ray(scene, &transmission_ray, &next_cone, depth + 1, rng);
+                }
+            }
+            let reflection_ray =
+                Ray::create_reflection(facing_normal, ray.direction, hit_point, scene.shadow_bias);
+            let reflection_color = cast_ray(scene, &reflection_ray, &next_cone, depth + 1, rng);
 
-    let mut color = shade_diffuse(scene, intersection.element, hit_point, surface_normal);
-    if let SurfaceType::Reflective { reflectivity } = intersection.element.material().surface {
-        let reflection_ray =
-            Ray::create_reflection(surface_normal, ray.direction, hit_point, scene.shadow_bias);
-        color = color * (1.0 - reflectivity);
-        color = color + (cast_ray(scene, &reflection_ray, depth + 1) * reflectivity);
+            let transmitted =
+                (reflection_color * kr + refraction_color * (1.0 - kr)) * shading.color;
+            color = color * (1.0 - transparency) + transmitted * transparency;
+        }
     }
     color
 }
 
-pub fn cast_ray(scene: &Scene, ray: &Ray, depth: u32) -> Color {
+pub fn cast_ray(scene: &Scene, ray: &Ray, cone: &RayCone, depth: u32, rng: &mut Rng) -> Color {
     if depth >= scene.max_recursion_depth {
         return BLACK;
     }
 
-    let intersection = scene.trace(&ray);
+    let intersection = scene.trace(ray);
     intersection
-        .map(|i| get_color(scene, &ray, &i, depth))
+        .map(|i| get_color(scene, ray, cone, &i, depth, rng))
         .unwrap_or(BLACK)
 }
@@ -0,0 +1,120 @@
+// Random numbers and sample placement. We don't pull in the rand crate: all we need is a small,
+// fast generator that can be seeded per pixel, so renders are repeatable no matter how the work is
+// split across threads.
+
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+
+// PCG32 (https://www.pcg-random.org/)
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+const PCG_MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+const PCG_INCREMENT: u64 = 1_442_695_040_888_963_407;
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        let mut rng = Rng { state: 0 };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+
+    // Seed for a given pixel; neighbouring pixels get unrelated sequences
+    pub fn for_pixel(x: u32, y: u32) -> Rng {
+        Rng::for_pixel_pass(x, y, 0)
+    }
+
+    // Seed for a given pixel in one pass of a progressive render, so each pass adds new samples
+    // rather than repeating the last. Pass 0 is the same as for_pixel.
+    pub fn for_pixel_pass(x: u32, y: u32, pass: u32) -> Rng {
+        let pixel = ((y as u64) << 32 | x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        Rng::new(pixel.wrapping_add((pass as u64).wrapping_mul(0xD1B5_4A32_D192_ED03)))
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(PCG_MULTIPLIER).wrapping_add(PCG_INCREMENT);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    // Uniform in 0.0..1.0 (never returns 1.0)
+    pub fn next_f64(&mut self) -> f64 {
+        self.next_u32() as f64 / (u32::MAX as f64 + 1.0)
+    }
+}
+
+// Where in a pixel the samples for that pixel go
+#[derive(Copy, Clone, Debug)]
+pub enum SamplePattern {
+    Grid,     // evenly spaced, same positions in every pixel
+    Jittered, // one random sample per grid cell (aka stratified)
+    Random,   // uniformly random over the whole pixel
+}
+
+// Returns `count` sample positions within a pixel, each in 0.0..1.0 x 0.0..1.0 with (0, 0) the
+// top left corner. A single grid sample is the pixel centre.
+pub fn pixel_offsets(pattern: SamplePattern, count: u32, rng: &mut Rng) -> Vec<(f64, f64)> {
+    let count = count.max(1);
+    if let SamplePattern::Random = pattern {
+        return (0..count)
+            .map(|_| (rng.next_f64(), rng.next_f64()))
+            .collect();
+    }
+
+    // Split the pixel into roughly sqrt(count) rows. When count isn't a perfect square some rows
+    // get one more sample than others, but every row is the same height and each row's samples
+    // are evenly spread across it, so the whole pixel is still covered.
+    let rows = (count as f64).sqrt().round() as u32;
+    let mut offsets = Vec::with_capacity(count as usize);
+    for row in 0..rows {
+        let columns = (count * (row + 1)) / rows - (count * row) / rows;
+        for column in 0..columns {
+            let (jitter_x, jitter_y) = match pattern {
+                SamplePattern::Jittered => (rng.next_f64(), rng.next_f64()),
+                _ => (0.5, 0.5),
+            };
+            offsets.push((
+                (column as f64 + jitter_x) / columns as f64,
+                (row as f64 + jitter_y) / rows as f64,
+            ));
+        }
+    }
+    offsets
+}
+
+// Uniformly distributed point on the unit disc, using the concentric mapping (Shirley and Chiu
+// 1997) which keeps stratified samples evenly spread
+pub fn uniform_disc(rng: &mut Rng) -> (f64, f64) {
+    let x = 2.0 * rng.next_f64() - 1.0;
+    let y = 2.0 * rng.next_f64() - 1.0;
+    if x == 0.0 && y == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (radius, theta) = if x.abs() > y.abs() {
+        (x, FRAC_PI_4 * (y / x))
+    } else {
+        (y, FRAC_PI_2 - FRAC_PI_4 * (x / y))
+    };
+    (radius * theta.cos(), radius * theta.sin())
+}
+
+// Uniformly distributed direction within a cone around +z whose half angle has the given cosine.
+// Returned as (x, y, z) in that frame.
+pub fn uniform_cone(rng: &mut Rng, cos_max: f64) -> (f64, f64, f64) {
+    let cos_theta = 1.0 - rng.next_f64() * (1.0 - cos_max);
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.next_f64();
+    (sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+// Direction on the hemisphere around +z, more likely the closer it is to +z: the density is
+// cos(theta) / pi, which cancels the cosine in the rendering equation for diffuse surfaces
+pub fn cosine_hemisphere(rng: &mut Rng) -> (f64, f64, f64) {
+    let (x, y) = uniform_disc(rng);
+    (x, y, (1.0 - x * x - y * y).max(0.0).sqrt())
+}
@@ -1,59 +1,120 @@
+use crate::bvh::Bvh;
+use crate::camera::Camera;
 use crate::color::Color;
 use crate::point::Point;
-use crate::rendering::{Intersectable, Ray, TextureCoords};
+use crate::procedural::Procedural;
+pub use crate::rendering::TextureCoords;
+use crate::rendering::{Hit, Intersectable, Ray};
+use crate::sampling::{self, Rng};
+use crate::texture::{Texture, TextureLookup};
 use crate::vector::Vector3;
-use image::{DynamicImage, GenericImageView};
-use std::fmt::{Error, Formatter};
+use std::f32::consts::PI;
+use std::sync::Arc;
 
+#[derive(Clone, Debug)]
 pub enum Coloration {
     Color(Color),
-    Texture(DynamicImage),
+    Texture(Texture),
+    Procedural(Procedural),
 }
 
-impl std::fmt::Debug for Coloration {
-    fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
+impl Coloration {
+    pub fn color(&self, lookup: &TextureLookup) -> Color {
         match *self {
-            Coloration::Color(ref c) => c.fmt(f),
-            Coloration::Texture(_) => write!(f, "RgbaImage"),
+            Coloration::Color(c) => c,
+            Coloration::Texture(ref texture) => {
+                texture.sample(&lookup.coords, &lookup.differentials)
+            }
+            Coloration::Procedural(ref procedural) => procedural.color(lookup),
         }
     }
 }
 
-fn wrap(val: f32, bound: u32) -> u32 {
-    let signed_bound = bound as i32;
-    let float_coord = val * bound as f32;
-    let wrapped_coord = (float_coord as i32) % signed_bound;
-    if wrapped_coord < 0 {
-        (wrapped_coord + signed_bound) as u32
-    } else {
-        wrapped_coord as u32
-    }
+// Detail in the shading normal that isn't in the geometry, so flat surfaces catch the light as if
+// they were rough, dented or tiled
+#[derive(Clone, Debug)]
+pub enum NormalMap {
+    // Tangent space normals encoded as colours: red along the texture's x, green up the image
+    // (against its y) and blue out of the surface, each mapped from -1..1 to 0..1. Should be
+    // loaded with linear encoding.
+    Normals(Texture),
+    // Heights above the surface, from the brightness of the coloration times `scale` in scene
+    // units. The normal follows the slopes.
+    Bump { height: Coloration, scale: f32 },
 }
 
-impl Coloration {
-    pub fn color(&self, coords: &TextureCoords) -> Color {
+// Deepest max_recursion_depth allowed. Each level of reflection or refraction is a nested call, so
+// much deeper could overflow the stack, and real scenes stop changing long before this.
+pub const RECURSION_DEPTH_LIMIT: u32 = 100;
+
+// Height of a bump map's brightest parts, in scene units, unless the material says otherwise
+pub const DEFAULT_BUMP_SCALE: f32 = 0.01;
+
+// A number describing a material that can vary over the surface, e.g. reflectivity that's lower
+// where a floor is scuffed
+#[derive(Clone, Debug)]
+pub enum Parameter {
+    Constant(f32),
+    // The brightness of the coloration times `scale`. Images used as maps should be loaded with
+    // linear encoding, so their values are used as they are.
+    Map { map: Coloration, scale: f32 },
+}
+
+impl Parameter {
+    pub fn value(&self, lookup: &TextureLookup) -> f32 {
         match *self {
-            Coloration::Color(c) => c.clone(),
-            Coloration::Texture(ref tex) => {
-                let tex_x = wrap(coords.x, tex.width());
-                let tex_y = wrap(coords.y, tex.height());
-                Color::from_rgba(tex.get_pixel(tex_x, tex_y))
-            }
+            Parameter::Constant(value) => value,
+            Parameter::Map { ref map, scale } => map.color(lookup).luminance() * scale,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum SurfaceType {
     Diffuse,
-    Reflective { reflectivity: f32 },
+    Reflective {
+        reflectivity: Parameter,
+    },
+    // Glass, water, etc. Index is the refractive index (1.0 is air), transparency is how much of
+    // the colour comes from light passing through/reflecting off vs diffuse shading.
+    Refractive {
+        index: Parameter,
+        transparency: Parameter,
+    },
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Material {
     pub coloration: Coloration,
-    pub albedo: f32,
+    // Scales the diffuse light. The Whitted integrator uses it as it is, as a brightness control,
+    // but path tracing caps albedo times colour at 1 in each channel so bounces never add energy.
+    pub albedo: Parameter,
     pub surface: SurfaceType,
+    // Blinn-Phong highlights. Black for none; the higher the exponent the smaller and sharper the
+    // highlight.
+    pub specular_color: Color,
+    pub specular_exponent: Parameter,
+    pub normal_map: Option<NormalMap>,
+}
+
+impl Material {
+    pub fn shading_parameters(&self, lookup: &TextureLookup) -> ShadingParameters {
+        ShadingParameters {
+            color: self.coloration.color(lookup),
+            albedo: self.albedo.value(lookup),
+            specular_color: self.specular_color,
+            specular_exponent: self.specular_exponent.value(lookup),
+        }
+    }
+}
+
+// What direct lighting needs to know about a material, as it is at one point on the surface
+#[derive(Copy, Clone, Debug)]
+pub struct ShadingParameters {
+    pub color: Color,
+    pub albedo: f32,
+    pub specular_color: Color,
+    pub specular_exponent: f32,
 }
 
 #[derive(Debug)]
@@ -76,10 +137,36 @@ pub struct Plane {
     pub material: Material,
 }
 
+#[derive(Clone, Debug)]
+pub struct Vertex {
+    pub position: Point,
+    pub normal: Option<Vector3>, // for smooth shading; used only if all of a face's vertices have one
+    pub texture_coords: Option<TextureCoords>,
+}
+
+// A lone triangle. The front face is the one the vertices wind counter-clockwise around.
+#[derive(Debug)]
+pub struct Triangle {
+    pub vertices: [Vertex; 3],
+    pub material: Material,
+}
+
+// Triangles indexing into a vertex buffer. The buffer is reference counted so several meshes (e.g.
+// the parts of a model that use different materials) can share one without copying it.
+#[derive(Debug)]
+pub struct Mesh {
+    pub vertices: Arc<Vec<Vertex>>,
+    pub faces: Vec<[usize; 3]>, // indexes into vertices, counter-clockwise around the front face
+    pub material: Material,
+    pub bvh: Option<Bvh>, // built by prepare; without it every face is tested
+}
+
 #[derive(Debug)]
 pub enum Element {
     Sphere(Sphere),
     Plane(Plane),
+    Triangle(Triangle),
+    Mesh(Mesh),
 }
 
 impl Element {
@@ -87,6 +174,8 @@ impl Element {
         match self {
             Element::Sphere(s) => &s.material,
             Element::Plane(p) => &p.material,
+            Element::Triangle(t) => &t.material,
+            Element::Mesh(m) => &m.material,
         }
     }
 }
@@ -94,15 +183,20 @@ impl Element {
 #[derive(Debug)]
 pub struct Intersection<'a> {
     pub distance: f64,
+    pub primitive: usize, // see Hit::primitive
     pub element: &'a Element,
 }
 
 impl<'a> Intersection<'a> {
-    pub fn new(distance: f64, element: &Element) -> Intersection {
-        if !distance.is_finite() {
+    pub fn new(hit: Hit, element: &Element) -> Intersection<'_> {
+        if !hit.distance.is_finite() {
             panic!("Intersection must have finite distance");
         }
-        Intersection { distance, element }
+        Intersection {
+            distance: hit.distance,
+            primitive: hit.primitive,
+            element,
+        }
     }
 }
 
@@ -114,18 +208,95 @@ pub struct DirectionalLight {
     pub intensity: f32,
 }
 
-// Point source light near the scene
+// Light near the scene, radiating equally in all directions. With a radius of zero it's a point
+// and casts hard shadows; otherwise it's a glowing ball, sampled `samples` times per shading point
+// for soft ones.
 #[derive(Debug)]
 pub struct SphericalLight {
     pub position: Point,
+    pub radius: f64,
+    pub color: Color,
+    pub intensity: f32,
+    pub samples: u32,
+}
+
+// Flat parallelogram with its centre at `position` and edges `u` and `v`, e.g. a ceiling panel.
+// It shines from both faces.
+#[derive(Debug)]
+pub struct RectangleLight {
+    pub position: Point,
+    pub u: Vector3,
+    pub v: Vector3,
+    pub color: Color,
+    pub intensity: f32,
+    pub samples: u32,
+}
+
+// Flat disc facing along `normal` (and the opposite way; it shines from both faces)
+#[derive(Debug)]
+pub struct DiscLight {
+    pub position: Point,
+    pub normal: Vector3,
+    pub radius: f64,
     pub color: Color,
     pub intensity: f32,
+    pub samples: u32,
+}
+
+// Point light that only shines within a cone around `direction`, like a stage light. Full
+// brightness within `inner_angle` of the direction, fading to nothing at `outer_angle` (both in
+// degrees, measured from the centre of the cone); `falloff` shapes the fade, higher being sharper.
+#[derive(Debug)]
+pub struct SpotLight {
+    pub position: Point,
+    pub direction: Vector3,
+    pub inner_angle: f64,
+    pub outer_angle: f64,
+    pub falloff: f32,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+impl SpotLight {
+    // How much of the light's brightness goes in the given (unit) direction
+    fn cone_attenuation(&self, direction: Vector3) -> f32 {
+        let cos_angle = direction.dot(&self.direction.normalize());
+        let cos_inner = self.inner_angle.to_radians().cos();
+        let cos_outer = self.outer_angle.to_radians().cos();
+        if cos_angle >= cos_inner {
+            1.0
+        } else if cos_angle <= cos_outer {
+            0.0
+        } else {
+            (((cos_angle - cos_outer) / (cos_inner - cos_outer)) as f32).powf(self.falloff)
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Light {
     Directional(DirectionalLight),
     Spherical(SphericalLight),
+    Rectangle(RectangleLight),
+    Disc(DiscLight),
+    Spot(SpotLight),
+}
+
+// One point on a light, as seen from a point being shaded
+pub struct LightSample {
+    pub direction: Vector3, // unit vector from the shaded point towards the light
+    pub distance: f64,      // how far the light is in that direction; shadows beyond it don't count
+    pub intensity: f32,     // light arriving from it, before the cosine at the surface
+    // Probability density (per solid angle) of having picked this direction. None for lights with
+    // no area, which can only be reached by sampling them.
+    pub pdf: Option<f64>,
+}
+
+// Where a ray runs into a light with area
+pub struct LightHit {
+    pub distance: f64,
+    pub radiance: f32, // light given off towards the ray's origin
+    pub pdf: f64,      // density with which sample() would have picked this direction
 }
 
 impl Light {
@@ -133,52 +304,467 @@ impl Light {
         match *self {
             Light::Directional(ref d) => d.color,
             Light::Spherical(ref s) => s.color,
+            Light::Rectangle(ref r) => r.color,
+            Light::Disc(ref d) => d.color,
+            Light::Spot(ref s) => s.color,
         }
     }
 
-    // Returns a vector pointing to the light from the given point.
-    pub fn direction_from(&self, hit_point: &Point) -> Vector3 {
+    // How many samples to average per shading point. Lights with no area only need one.
+    pub fn sample_count(&self) -> u32 {
         match *self {
-            Light::Directional(ref d) => -d.direction,
-            Light::Spherical(ref s) => (s.position - *hit_point).normalize(),
+            Light::Directional(_) | Light::Spot(_) => 1,
+            Light::Spherical(ref s) if s.radius <= 0.0 => 1,
+            Light::Spherical(ref s) => s.samples.max(1),
+            Light::Rectangle(ref r) => r.samples.max(1),
+            Light::Disc(ref d) => d.samples.max(1),
         }
     }
 
-    pub fn intensity(&self, hit_point: &Point) -> f32 {
+    // Picks a random point on the light as seen from `hit_point`. Intensities are set up so that
+    // averaging over many samples gives the light's total contribution, and for every kind of
+    // light `intensity` is the total power it gives off.
+    pub fn sample(&self, hit_point: &Point, rng: &mut Rng) -> LightSample {
         match *self {
-            Light::Directional(ref d) => d.intensity,
+            Light::Directional(ref d) => {
+                // The direction needn't be unit length, and a longer one makes the light brighter
+                let length = d.direction.length();
+                LightSample {
+                    direction: -d.direction * length.recip(),
+                    distance: f64::INFINITY,
+                    intensity: d.intensity * length as f32,
+                    pdf: None,
+                }
+            }
+            Light::Spherical(ref s) if s.radius <= 0.0 => {
+                let to_light = s.position - *hit_point;
+                let distance = to_light.length();
+                LightSample {
+                    direction: to_light * distance.recip(),
+                    distance,
+                    intensity: s.intensity / (4.0 * PI * to_light.dot(&to_light) as f32),
+                    pdf: None,
+                }
+            }
             Light::Spherical(ref s) => {
-                let p = s.position - *hit_point;
-                let r2 = (p.x * p.x + p.y * p.y + p.z * p.z) as f32;
-                s.intensity / (4.0 * ::std::f32::consts::PI * r2)
+                // Sample the cone of directions in which the sphere is visible, rather than its
+                // whole surface, half of which faces away
+                let to_center = s.position - *hit_point;
+                let center_distance = to_center.length();
+                let axis = to_center * center_distance.recip();
+                let cos_max = s.cone_cos_max(center_distance);
+                let (x, y, z) = sampling::uniform_cone(rng, cos_max);
+                let (tangent, bitangent) = axis.orthonormal_basis();
+                let direction = tangent * x + bitangent * y + axis * z;
+                // Nearest intersection of the sample direction with the sphere
+                let b = center_distance * z;
+                let distance = (b
+                    - (s.radius * s.radius - center_distance * center_distance + b * b)
+                        .max(0.0)
+                        .sqrt())
+                .max(0.0);
+                // A uniformly bright ball: radiance times the solid angle it covers
+                let solid_angle = 2.0 * std::f64::consts::PI * (1.0 - cos_max);
+                LightSample {
+                    direction,
+                    distance,
+                    intensity: s.radiance() * solid_angle as f32,
+                    pdf: Some(solid_angle.recip()),
+                }
+            }
+            Light::Spot(ref s) => {
+                // A point light, as far as it shines
+                let to_light = s.position - *hit_point;
+                let distance = to_light.length();
+                let direction = to_light * distance.recip();
+                let intensity = s.intensity / (4.0 * PI * to_light.dot(&to_light) as f32);
+                LightSample {
+                    direction,
+                    distance,
+                    intensity: intensity * s.cone_attenuation(-direction),
+                    pdf: None,
+                }
+            }
+            Light::Rectangle(ref r) => {
+                let point =
+                    r.position + r.u * (rng.next_f64() - 0.5) + r.v * (rng.next_f64() - 0.5);
+                area_sample(hit_point, point, r.normal(), r.area(), r.intensity)
+            }
+            Light::Disc(ref d) => {
+                let (x, y) = sampling::uniform_disc(rng);
+                let normal = d.normal.normalize();
+                let (tangent, bitangent) = normal.orthonormal_basis();
+                let point = d.position + (tangent * x + bitangent * y) * d.radius;
+                area_sample(hit_point, point, normal, d.area(), d.intensity)
             }
         }
     }
 
-    pub fn distance(&self, hit_point: &Point) -> f64 {
+    // Where the ray first runs into the light, for lights with area. Lights aren't part of the
+    // scene's geometry, so this is how rays that weren't aimed at a light can still find one.
+    pub fn hit(&self, ray: &Ray) -> Option<LightHit> {
         match *self {
-            Light::Directional(_) => ::std::f64::INFINITY,
-            Light::Spherical(ref s) => (s.position - *hit_point).length(),
+            Light::Spherical(ref s) if s.radius > 0.0 => {
+                let to_center = s.position - ray.origin;
+                let center_distance = to_center.length();
+                let b = to_center.dot(&ray.direction);
+                let discriminant =
+                    s.radius * s.radius - (center_distance * center_distance - b * b);
+                if discriminant < 0.0 {
+                    return None;
+                }
+                let half_chord = discriminant.sqrt();
+                let distance = if b - half_chord > 0.0 {
+                    b - half_chord
+                } else {
+                    b + half_chord
+                };
+                if distance <= 0.0 {
+                    return None;
+                }
+                let solid_angle =
+                    2.0 * std::f64::consts::PI * (1.0 - s.cone_cos_max(center_distance));
+                Some(LightHit {
+                    distance,
+                    radiance: s.radiance(),
+                    pdf: solid_angle.recip(),
+                })
+            }
+            Light::Rectangle(ref r) => {
+                let normal = r.u.cross(&r.v);
+                let distance = plane_distance(ray, &r.position, &normal)?;
+                // Position in terms of the edges; inside if both are within -0.5..0.5
+                let offset = (ray.origin + ray.direction * distance) - r.position;
+                let area_squared = normal.dot(&normal);
+                let a = offset.cross(&r.v).dot(&normal) / area_squared;
+                let b = r.u.cross(&offset).dot(&normal) / area_squared;
+                if a.abs() > 0.5 || b.abs() > 0.5 {
+                    return None;
+                }
+                Some(area_hit(ray, distance, r.normal(), r.area(), r.intensity))
+            }
+            Light::Disc(ref d) => {
+                let distance = plane_distance(ray, &d.position, &d.normal)?;
+                let offset = (ray.origin + ray.direction * distance) - d.position;
+                if offset.length() > d.radius {
+                    return None;
+                }
+                Some(area_hit(
+                    ray,
+                    distance,
+                    d.normal.normalize(),
+                    d.area(),
+                    d.intensity,
+                ))
+            }
+            _ => None,
         }
     }
 }
 
+impl SphericalLight {
+    // Radiance of a uniformly bright ball giving off `intensity` in total
+    fn radiance(&self) -> f32 {
+        self.intensity / (4.0 * PI * PI * (self.radius * self.radius) as f32)
+    }
+
+    // Cosine of the half angle of the cone the ball fills, seen from the given distance from its
+    // centre. Zero (a whole hemisphere) from inside it.
+    fn cone_cos_max(&self, center_distance: f64) -> f64 {
+        let sin2_max = (self.radius / center_distance).powi(2);
+        (1.0 - sin2_max).max(0.0).sqrt()
+    }
+}
+
+impl RectangleLight {
+    fn normal(&self) -> Vector3 {
+        self.u.cross(&self.v).normalize()
+    }
+
+    fn area(&self) -> f64 {
+        self.u.cross(&self.v).length()
+    }
+}
+
+impl DiscLight {
+    fn area(&self) -> f64 {
+        std::f64::consts::PI * self.radius * self.radius
+    }
+}
+
+// Radiance of a two sided Lambertian emitter giving off `power` in total
+fn flat_radiance(power: f32, area: f64) -> f32 {
+    power / (2.0 * PI * area as f32)
+}
+
+// Density per solid angle of picking a point on a flat light uniformly by area, converted from
+// per area by the distance and the angle the light is seen at
+fn flat_pdf(distance: f64, cos_light: f64, area: f64) -> f64 {
+    distance * distance / (cos_light * area)
+}
+
+// Sample for a point picked uniformly on a flat light of the given area that shines from both
+// faces
+fn area_sample(
+    hit_point: &Point,
+    light_point: Point,
+    light_normal: Vector3,
+    area: f64,
+    power: f32,
+) -> LightSample {
+    let to_light = light_point - *hit_point;
+    let distance = to_light.length();
+    let direction = to_light * distance.recip();
+    let cos_light = direction.dot(&light_normal).abs();
+    LightSample {
+        direction,
+        distance,
+        intensity: flat_radiance(power, area) * (cos_light * area / (distance * distance)) as f32,
+        pdf: Some(flat_pdf(distance, cos_light, area)),
+    }
+}
+
+fn area_hit(ray: &Ray, distance: f64, light_normal: Vector3, area: f64, power: f32) -> LightHit {
+    let cos_light = ray.direction.dot(&light_normal).abs();
+    LightHit {
+        distance,
+        radiance: flat_radiance(power, area),
+        pdf: flat_pdf(distance, cos_light, area),
+    }
+}
+
+// Distance along the ray to the plane through `point` with the given normal, if it's ahead
+fn plane_distance(ray: &Ray, point: &Point, normal: &Vector3) -> Option<f64> {
+    let denominator = normal.dot(&ray.direction);
+    if denominator == 0.0 {
+        return None;
+    }
+    let distance = (*point - ray.origin).dot(normal) / denominator;
+    if distance > 0.0 {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
 #[derive(Debug)]
 pub struct Scene {
     pub width: u32,
     pub height: u32,
-    pub fov: f64,
+    pub camera: Camera,
     pub elements: Vec<Element>,
     pub lights: Vec<Light>,
     pub shadow_bias: f64, // hack to ensure intersection points are outside their elements
     pub max_recursion_depth: u32,
+    pub bvh: Option<Bvh>, // built by prepare; without it trace tests every element
 }
 
 impl Scene {
-    pub fn trace(&self, ray: &Ray) -> Option<Intersection> {
-        self.elements
+    // Builds the acceleration structures trace uses, for the scene and for each mesh. Call again
+    // after changing the elements.
+    pub fn prepare(&mut self) {
+        for element in &mut self.elements {
+            if let Element::Mesh(ref mut mesh) = element {
+                mesh.prepare();
+            }
+        }
+        let bounds: Vec<_> = self.elements.iter().map(|e| e.bounding_box()).collect();
+        self.bvh = Some(Bvh::build(&bounds));
+    }
+
+    pub fn trace(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        match self.bvh {
+            Some(ref bvh) => bvh
+                .intersect(ray, |i| self.elements[i].intersect(ray))
+                .map(|(i, hit)| Intersection::new(hit, &self.elements[i])),
+            None => self
+                .elements
+                .iter()
+                .filter_map(|e| e.intersect(ray).map(|hit| Intersection::new(hit, e)))
+                .min_by(|i1, i2| i1.distance.partial_cmp(&i2.distance).unwrap()),
+        }
+    }
+
+    // Whether anything is hit closer than max_distance along the ray. Cheaper than trace since
+    // any hit will do, not just the nearest.
+    pub fn occluded(&self, ray: &Ray, max_distance: f64) -> bool {
+        match self.bvh {
+            Some(ref bvh) => bvh.occluded(ray, max_distance, |i| {
+                self.elements[i].occludes(ray, max_distance)
+            }),
+            None => self.elements.iter().any(|e| e.occludes(ray, max_distance)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material() -> Material {
+        Material {
+            coloration: Coloration::Color(Color {
+                red: 1.0,
+                green: 1.0,
+                blue: 1.0,
+            }),
+            albedo: Parameter::Constant(1.0),
+            surface: SurfaceType::Diffuse,
+            specular_color: Color {
+                red: 0.0,
+                green: 0.0,
+                blue: 0.0,
+            },
+            specular_exponent: Parameter::Constant(1.0),
+            normal_map: None,
+        }
+    }
+
+    fn point(rng: &mut Rng, size: f64) -> Point {
+        Point {
+            x: (rng.next_f64() * 2.0 - 1.0) * size,
+            y: (rng.next_f64() * 2.0 - 1.0) * size,
+            z: (rng.next_f64() * 2.0 - 1.0) * size,
+        }
+    }
+
+    fn vertex(position: Point) -> Vertex {
+        Vertex {
+            position,
+            normal: None,
+            texture_coords: None,
+        }
+    }
+
+    // Spheres scattered through a box, a bumpy 500 face mesh, a lone triangle and a tilted plane.
+    // Built afresh for each scene as elements can't be cloned.
+    fn elements() -> Vec<Element> {
+        let mut rng = Rng::new(7);
+        let mut elements = Vec::new();
+        for _ in 0..300 {
+            elements.push(Element::Sphere(Sphere {
+                center: point(&mut rng, 10.0),
+                radius: 0.1 + rng.next_f64() * 0.5,
+                material: material(),
+            }));
+        }
+
+        let (columns, rows) = (25, 10);
+        let mut vertices = Vec::new();
+        for row in 0..=rows {
+            for column in 0..=columns {
+                vertices.push(vertex(Point {
+                    x: column as f64 - 12.5,
+                    y: row as f64 - 5.0,
+                    z: -12.0 + rng.next_f64(),
+                }));
+            }
+        }
+        let mut faces = Vec::new();
+        for row in 0..rows {
+            for column in 0..columns {
+                let corner = row * (columns + 1) + column;
+                let above = corner + columns + 1;
+                faces.push([corner, corner + 1, above + 1]);
+                faces.push([corner, above + 1, above]);
+            }
+        }
+        assert_eq!(faces.len(), 500);
+        elements.push(Element::Mesh(Mesh {
+            vertices: Arc::new(vertices),
+            faces,
+            material: material(),
+            bvh: None,
+        }));
+
+        elements.push(Element::Triangle(Triangle {
+            vertices: [
+                vertex(point(&mut rng, 10.0)),
+                vertex(point(&mut rng, 10.0)),
+                vertex(point(&mut rng, 10.0)),
+            ],
+            material: material(),
+        }));
+        elements.push(Element::Plane(Plane {
+            origin: Point {
+                x: 0.0,
+                y: -11.0,
+                z: 0.0,
+            },
+            normal: Vector3 {
+                x: 0.1,
+                y: 1.0,
+                z: 0.2,
+            }
+            .normalize(),
+            material: material(),
+        }));
+        elements
+    }
+
+    fn scene() -> Scene {
+        Scene {
+            width: 1,
+            height: 1,
+            camera: Camera::default(),
+            elements: elements(),
+            lights: Vec::new(),
+            shadow_bias: 1e-6,
+            max_recursion_depth: 1,
+            bvh: None,
+        }
+    }
+
+    fn index(scene: &Scene, element: &Element) -> usize {
+        scene
+            .elements
             .iter()
-            .filter_map(|e| e.intersect(ray).map(|d| Intersection::new(d, e)))
-            .min_by(|i1, i2| i1.distance.partial_cmp(&i2.distance).unwrap())
+            .position(|e| std::ptr::eq(e, element))
+            .unwrap()
+    }
+
+    #[test]
+    fn bvh_finds_the_same_hits_as_testing_every_element() {
+        let brute_force = scene();
+        let mut prepared = scene();
+        prepared.prepare();
+
+        let mut rng = Rng::new(11);
+        let mut hits = 0;
+        let mut occluded = 0;
+        for _ in 0..5000 {
+            let ray = Ray {
+                origin: point(&mut rng, 15.0),
+                direction: (point(&mut rng, 1.0) - Point::zero()).normalize(),
+            };
+            let expected = brute_force.trace(&ray);
+            let actual = prepared.trace(&ray);
+            match (&expected, &actual) {
+                (None, None) => {}
+                (Some(expected), Some(actual)) => {
+                    hits += 1;
+                    assert_eq!(expected.distance, actual.distance, "{:?}", ray);
+                    assert_eq!(expected.primitive, actual.primitive, "{:?}", ray);
+                    assert_eq!(
+                        index(&brute_force, expected.element),
+                        index(&prepared, actual.element),
+                        "{:?}",
+                        ray
+                    );
+                }
+                _ => panic!(
+                    "{:?}: {:?} without the BVH, {:?} with",
+                    ray, expected, actual
+                ),
+            }
+
+            let max_distance = rng.next_f64() * 30.0;
+            let expected = brute_force.occluded(&ray, max_distance);
+            assert_eq!(expected, prepared.occluded(&ray, max_distance), "{:?}", ray);
+            occluded += expected as u32;
+        }
+        // Make sure the rays actually exercised both outcomes
+        assert!(hits > 1000 && hits < 5000, "{} hits", hits);
+        assert!(occluded > 500 && occluded < 4500, "{} occluded", occluded);
     }
 }
@@ -0,0 +1,1404 @@
+// Plain text scene descriptions, so scenes can be changed without recompiling.
+//
+// The format is line based. Each line is either a field (`name value value ...`), the start of a
+// block (`kind {` or `kind name {`) or the end of one (`}`). `#` starts a comment. Values
+// containing spaces can be "double quoted". Relative paths are relative to the scene file. e.g.
+//
+//     width 1600
+//     height 900
+//
+//     camera {
+//         position 0 0 0
+//         look_at 0 0 -1
+//         fov 90
+//     }
+//
+//     material shiny_green {
+//         color 0 1 0
+//         albedo 5
+//         reflectivity 0.3
+//         specular_color 1 1 1
+//     }
+//
+//     sphere {
+//         center 0.3 0.5 -3
+//         radius 0.85
+//         material shiny_green
+//     }
+//
+//     plane {
+//         origin 0 -2 0
+//         normal 0 -1 0
+//         material {
+//             texture checkerboard.png
+//         }
+//     }
+//
+//     spherical_light {
+//         position -1 1.5 -1
+//         color 0.1 0.1 0.8
+//         intensity 100
+//     }
+//
+// Materials have a `color` or a `texture`, an `albedo` (default 1) and are diffuse unless given a
+// `reflectivity`, or a `refractive_index` and `transparency` (default 1) for glass-like surfaces.
+// The Whitted integrator scales diffuse light by any albedo, but the path tracer caps albedo times
+// colour at 1 in each channel, so an albedo above 1 only brightens it up to a white surface.
+// Textures are smoothed where they're seen small according to their `texture_filter`: nearest,
+// bilinear, trilinear (the default) or anisotropic (sharper on surfaces seen side on). Beyond their
+// edges they repeat unless `texture_addressing` is mirror, clamp or `border r g b`. Texture coords
+// are scaled by `texture_scale` (one value, or separate u and v), rotated `texture_rotation`
+// degrees and moved by `texture_offset u v`. Planes' texture coords are in world units, so e.g. a
+// scale of 0.5 stretches a texture over 2x2 units.
+//
+// Instead of a color or texture, a material can have a `pattern` block, computed as it's rendered.
+// Its `type` is checker, stripes, grid (with a `line_width`, default 0.05), gradient, noise, fbm or
+// turbulence (with `octaves`, default 6), marble, wood or worley, and it shades from `color_a`
+// (default black) to `color_b` (default white). Patterns are laid on the surface by texture coords,
+// with a `scale`, `rotation` and `offset` as for textures, unless given `space solid`: then they
+// fill space, evaluated at the hit point times `scale`, so they wrap around spheres without seams.
+//
+//     material {
+//         pattern {
+//             type marble
+//             color_a 0.9 0.9 0.85
+//             color_b 0.3 0.3 0.35
+//             space solid
+//             scale 2
+//         }
+//     }
+//
+// Surfaces can be given detail the geometry doesn't have with a `normal_map`, an image of tangent
+// space normals (green pointing up the image, as OpenGL has them), or a `bump_map` image or
+// `bump_pattern` block whose brightness is height above the surface, up to `bump_scale` (default
+// 0.01) scene units. These images use the same texture settings as the material's texture.
+//
+// The albedo, reflectivity, refractive_index, transparency and specular_exponent can vary over the
+// surface: give a `_map` image or `_pattern` block (e.g. `reflectivity_map scuffs.png`) and the
+// brightness there is the value, multiplied by the plain field if that's given too. Maps are read
+// as data, not colours, so mid grey in the file is 0.5.
+//
+// A `specular_color` (default black, i.e. none) adds highlights from the lights, sharper the higher
+// the `specular_exponent` (default 32).
+//
+// Triangles and meshes list their vertices as `vertex x y z [normal x y z] [uv u v]`. Meshes then
+// refer to them by (zero based) index with `face a b c`.
+//
+//     mesh {
+//         vertex -1 0 -3 uv 0 0
+//         vertex 1 0 -3 uv 1 0
+//         vertex 1 1 -3 uv 1 1
+//         vertex -1 1 -3 uv 0 1
+//         face 0 1 2
+//         face 0 2 3
+//         material shiny_green
+//     }
+//
+// Models can be imported from Wavefront OBJ files. Their MTL materials are used where they exist;
+// the optional material here covers any faces without one.
+//
+//     obj {
+//         file teapot.obj
+//         material shiny_green
+//     }
+//
+// Spherical lights are points unless given a `radius`. Lights with area (spherical lights with a
+// radius, `rectangle_light` with a centre `position` and edges `u` and `v`, and `disc_light` with a
+// `position`, `normal` and `radius`) cast soft shadows, taking `samples` (default 16) shadow rays
+// per shaded point. Intensity is always the light's total power.
+//
+//     rectangle_light {
+//         position 0 3 -3
+//         u 2 0 0
+//         v 0 0 1
+//         color 1 1 1
+//         intensity 500
+//         samples 32
+//     }
+//
+// A `spot_light` is a point light limited to a cone: full brightness within `inner_angle` degrees
+// of its `direction`, fading out by `outer_angle`, with an optional `falloff` exponent (default 1)
+// shaping the fade.
+//
+//     spot_light {
+//         position 0 4 -3
+//         direction 0 -1 0
+//         inner_angle 15
+//         outer_angle 25
+//         color 1 1 1
+//         intensity 400
+//     }
+//
+// Top level fields: width, height, shadow_bias (default 1e-13), max_recursion_depth (default 3,
+// at most 100).
+// Blocks: camera, material, sphere, plane, triangle, mesh, obj, directional_light,
+// spherical_light, spot_light, rectangle_light, disc_light. Elements take their material either by
+// name or as a nested material block.
+
+use crate::camera::{Camera, CameraError, FovAxis};
+use crate::color::Color;
+use crate::obj;
+use crate::point::Point;
+use crate::procedural::{Pattern, PatternSpace, Procedural};
+use crate::scene::{
+    Coloration, DirectionalLight, DiscLight, Element, Light, Material, Mesh, NormalMap, Parameter,
+    Plane, RectangleLight, Scene, Sphere, SphericalLight, SpotLight, SurfaceType, TextureCoords,
+    Triangle, Vertex, DEFAULT_BUMP_SCALE, RECURSION_DEPTH_LIMIT,
+};
+use crate::texture::{
+    Texture, TextureAddressing, TextureCache, TextureEncoding, TextureFilter, UvTransform,
+};
+use crate::vector::Vector3;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Shadow rays per shaded point for lights with area, unless the scene says otherwise
+const DEFAULT_LIGHT_SAMPLES: u32 = 16;
+
+// For fbm and turbulence patterns
+const DEFAULT_OCTAVES: u32 = 6;
+
+// Material fields that can vary over the surface, by a `_map` image or `_pattern` block
+const PARAMETERS: [&str; 5] = [
+    "albedo",
+    "reflectivity",
+    "refractive_index",
+    "transparency",
+    "specular_exponent",
+];
+
+// Material fields that only make sense with a texture
+const TEXTURE_SETTINGS: [&str; 5] = [
+    "texture_filter",
+    "texture_addressing",
+    "texture_scale",
+    "texture_rotation",
+    "texture_offset",
+];
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    // Problem with the contents of the scene file. `field` is the field or block at fault.
+    Parse {
+        line: usize,
+        field: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io { path, error } => {
+                write!(f, "couldn't read {}: {}", path.display(), error)
+            }
+            SceneError::Parse {
+                line,
+                field,
+                message,
+            } => write!(f, "line {}: {}: {}", line, field, message),
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+type Result<T> = std::result::Result<T, SceneError>;
+
+impl Scene {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Scene> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|error| SceneError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Scene::parse(&source, path.parent().unwrap_or_else(|| Path::new("")))
+    }
+
+    // Relative paths in `source` (e.g. textures) are resolved against `base_dir`
+    pub fn parse(source: &str, base_dir: &Path) -> Result<Scene> {
+        let mut root = parse_blocks(source)?;
+        let loader = Loader {
+            base_dir,
+            textures: TextureCache::new(),
+        };
+
+        let width = root.required("width")?.positive()?;
+        let height = root.required("height")?.positive()?;
+        let shadow_bias = root.optional_or("shadow_bias", 1e-13, Field::value)?;
+        let depth_field = root.optional("max_recursion_depth")?;
+        let max_recursion_depth = depth_field.as_ref().map_or(Ok(3), Field::value)?;
+        if max_recursion_depth > RECURSION_DEPTH_LIMIT {
+            return Err(depth_field
+                .unwrap()
+                .error(&format!("must be at most {}", RECURSION_DEPTH_LIMIT)));
+        }
+
+        let mut camera = None;
+        let mut materials = HashMap::new();
+        let mut elements = Vec::new();
+        let mut lights = Vec::new();
+        for block in root.take_blocks() {
+            match block.kind.as_str() {
+                "camera" => {
+                    if camera.is_some() {
+                        return Err(block.error("only one camera is allowed"));
+                    }
+                    camera = Some(loader.camera(block)?);
+                }
+                "material" => {
+                    let name = block.name()?;
+                    if materials.contains_key(&name) {
+                        return Err(block.error(&format!("material `{}` is already defined", name)));
+                    }
+                    materials.insert(name, loader.material(block)?);
+                }
+                "sphere" => elements.push(Element::Sphere(loader.sphere(block, &materials)?)),
+                "plane" => elements.push(Element::Plane(loader.plane(block, &materials)?)),
+                "triangle" => elements.push(Element::Triangle(loader.triangle(block, &materials)?)),
+                "mesh" => elements.push(Element::Mesh(loader.mesh(block, &materials)?)),
+                "obj" => elements.extend(loader.obj(block, &materials)?),
+                "directional_light" => lights.push(loader.directional_light(block)?),
+                "spherical_light" => lights.push(loader.spherical_light(block)?),
+                "spot_light" => lights.push(loader.spot_light(block)?),
+                "rectangle_light" => lights.push(loader.rectangle_light(block)?),
+                "disc_light" => lights.push(loader.disc_light(block)?),
+                _ => return Err(block.error("unknown block")),
+            }
+        }
+        root.finish()?;
+
+        let mut scene = Scene {
+            width,
+            height,
+            camera: camera.unwrap_or_default(),
+            elements,
+            lights,
+            shadow_bias,
+            max_recursion_depth,
+            bvh: None,
+        };
+        scene.prepare();
+        Ok(scene)
+    }
+}
+
+struct Loader<'a> {
+    base_dir: &'a Path,
+    // Several materials (and OBJ files) often use the same image
+    textures: TextureCache,
+}
+
+impl<'a> Loader<'a> {
+    fn camera(&self, mut block: Block) -> Result<Camera> {
+        let default = Camera::default();
+        let position = block.optional("position")?;
+        let look_at = block.optional("look_at")?;
+        let up = block.optional("up")?;
+        let camera = Camera::new(
+            position
+                .as_ref()
+                .map_or(Ok(default.position()), Field::point)?,
+            look_at
+                .as_ref()
+                .map_or(Ok(default.look_at()), Field::point)?,
+            up.as_ref().map_or(Ok(default.up()), Field::vector)?,
+            block.optional_or("fov", default.fov(), Field::fov)?,
+            block.optional_or("fov_axis", default.fov_axis(), Field::fov_axis)?,
+        )
+        .map_err(|e| {
+            // Blame the field most likely to be wrong out of those given
+            let culprit = match e {
+                CameraError::NoViewDirection => look_at.as_ref().or(position.as_ref()),
+                CameraError::UpAlongView => up.as_ref().or(look_at.as_ref()).or(position.as_ref()),
+            };
+            match culprit {
+                Some(field) => field.error(&e.to_string()),
+                None => block.error(&e.to_string()),
+            }
+        })?;
+        block.finish()?;
+        Ok(camera)
+    }
+
+    fn material(&self, mut block: Block) -> Result<Material> {
+        let color = block.optional("color")?;
+        let texture = block.optional("texture")?;
+        let pattern = block.take_block("pattern")?;
+        let normal_map = block.optional("normal_map")?;
+        let bump_map = block.optional("bump_map")?;
+        let bump_pattern = block.take_block("bump_pattern")?;
+        let bump_scale = block.optional("bump_scale")?;
+        // The settings apply to all of the material's images
+        let has_images = texture.is_some()
+            || normal_map.is_some()
+            || bump_map.is_some()
+            || PARAMETERS
+                .iter()
+                .any(|key| block.contains(&format!("{}_map", key)));
+        let settings = if has_images {
+            TextureSettings::read(&mut block)?
+        } else {
+            for key in TEXTURE_SETTINGS {
+                if let Some(field) = block.optional(key)? {
+                    return Err(field.error(&format!("{} needs a texture", key)));
+                }
+            }
+            TextureSettings::default()
+        };
+        let coloration = match (color, texture, pattern) {
+            (Some(color), None, None) => Coloration::Color(color.color()?),
+            (None, Some(texture), None) => {
+                Coloration::Texture(self.texture(&texture, TextureEncoding::Srgb, &settings)?)
+            }
+            (None, None, Some(pattern)) => Coloration::Procedural(self.pattern(pattern)?),
+            (None, None, None) => return Err(block.missing("color")),
+            (_, Some(texture), _) => {
+                return Err(texture
+                    .error("a material can only have one of a color, a texture and a pattern"))
+            }
+            (Some(_), None, Some(pattern)) => {
+                return Err(pattern
+                    .error("a material can only have one of a color, a texture and a pattern"))
+            }
+        };
+        let scale = bump_scale
+            .as_ref()
+            .map_or(Ok(DEFAULT_BUMP_SCALE), Field::value)?;
+        let normal_map = match (normal_map, bump_map, bump_pattern) {
+            (None, None, None) => None,
+            (Some(normal_map), None, None) => Some(NormalMap::Normals(self.texture(
+                &normal_map,
+                TextureEncoding::Linear,
+                &settings,
+            )?)),
+            (None, Some(bump_map), None) => Some(NormalMap::Bump {
+                height: Coloration::Texture(self.texture(
+                    &bump_map,
+                    TextureEncoding::Linear,
+                    &settings,
+                )?),
+                scale,
+            }),
+            (None, None, Some(bump_pattern)) => Some(NormalMap::Bump {
+                height: Coloration::Procedural(self.pattern(bump_pattern)?),
+                scale,
+            }),
+            (Some(normal_map), _, _) => {
+                return Err(
+                    normal_map.error("a material can have a normal_map or a bump map, not both")
+                )
+            }
+            (None, Some(bump_map), Some(_)) => {
+                return Err(bump_map.error("give either a bump_map or a bump_pattern, not both"))
+            }
+        };
+        if let (Some(field), None | Some(NormalMap::Normals(_))) = (&bump_scale, &normal_map) {
+            return Err(field.error("bump_scale needs a bump_map or bump_pattern"));
+        }
+        let albedo = self.parameter(&mut block, "albedo", &settings)?;
+        let reflectivity = self.parameter(&mut block, "reflectivity", &settings)?;
+        let index = self.parameter(&mut block, "refractive_index", &settings)?;
+        let transparency = self.parameter(&mut block, "transparency", &settings)?;
+        let surface =
+            match (reflectivity, index, transparency) {
+                (None, None, None) => SurfaceType::Diffuse,
+                (Some((reflectivity, _)), None, None) => SurfaceType::Reflective { reflectivity },
+                (None, Some((index, _)), transparency) => SurfaceType::Refractive {
+                    index,
+                    transparency: transparency.map_or(Parameter::Constant(1.0), |(t, _)| t),
+                },
+                (Some((_, reflectivity)), Some(_), _) => {
+                    return Err(reflectivity.error(
+                        "a material can be reflective or refractive (refractive_index), not both",
+                    ))
+                }
+                (Some(_), None, Some((_, transparency))) => return Err(transparency.error(
+                    "transparency needs a refractive_index, and refractive materials can't have \
+                     a reflectivity",
+                )),
+                (None, None, Some((_, transparency))) => {
+                    return Err(transparency.error("transparency needs a refractive_index"))
+                }
+            };
+        let specular_color = block.optional_or("specular_color", Color::black(), Field::color)?;
+        let specular_exponent = self.parameter(&mut block, "specular_exponent", &settings)?;
+        block.finish()?;
+        Ok(Material {
+            coloration,
+            albedo: albedo.map_or(Parameter::Constant(1.0), |(albedo, _)| albedo),
+            surface,
+            specular_color,
+            specular_exponent: specular_exponent
+                .map_or(Parameter::Constant(32.0), |(exponent, _)| exponent),
+            normal_map,
+        })
+    }
+
+    // One of the PARAMETERS: `key value`, and/or a `key_map` image or `key_pattern` block, which
+    // the value (if given) multiplies. None if the material has none of them. Also returns where
+    // it was given, for errors about how it combines with other fields.
+    fn parameter(
+        &self,
+        block: &mut Block,
+        key: &str,
+        settings: &TextureSettings,
+    ) -> Result<Option<(Parameter, Given)>> {
+        let value_field = block.optional(key)?;
+        let value = value_field.as_ref().map(Field::value).transpose()?;
+        let map = block.optional(&format!("{}_map", key))?;
+        let pattern = block.take_block(&format!("{}_pattern", key))?;
+        let (map, given) = match (map, pattern) {
+            (None, None) => {
+                return Ok(value
+                    .zip(value_field)
+                    .map(|(value, field)| (Parameter::Constant(value), Given::Field(field))))
+            }
+            (Some(map), None) => (
+                Coloration::Texture(self.texture(&map, TextureEncoding::Linear, settings)?),
+                Given::Field(map),
+            ),
+            (None, Some(pattern)) => {
+                let given = Given::Block {
+                    kind: pattern.kind.clone(),
+                    line: pattern.line,
+                };
+                (Coloration::Procedural(self.pattern(pattern)?), given)
+            }
+            (Some(map), Some(_)) => {
+                return Err(map.error(&format!(
+                    "give either a {0}_map or a {0}_pattern, not both",
+                    key
+                )))
+            }
+        };
+        Ok(Some((
+            Parameter::Map {
+                map,
+                scale: value.unwrap_or(1.0),
+            },
+            given,
+        )))
+    }
+
+    // The image named by `field`, with the material's texture settings
+    fn texture(
+        &self,
+        field: &Field,
+        encoding: TextureEncoding,
+        settings: &TextureSettings,
+    ) -> Result<Texture> {
+        let path = self.base_dir.join(field.single()?);
+        let mut texture = self.textures.load(&path, encoding).map_err(|e| {
+            field.error(&format!("couldn't load texture {}: {}", path.display(), e))
+        })?;
+        settings.apply(&mut texture);
+        Ok(texture)
+    }
+
+    fn pattern(&self, mut block: Block) -> Result<Procedural> {
+        let kind = block.required("type")?;
+        let pattern = match kind.single()? {
+            "checker" => Pattern::Checker,
+            "stripes" => Pattern::Stripes,
+            "grid" => Pattern::Grid {
+                line_width: block.optional_or("line_width", 0.05, Field::value)?,
+            },
+            "gradient" => Pattern::Gradient,
+            "noise" => Pattern::Noise,
+            "fbm" => Pattern::Fbm {
+                octaves: block.optional_or("octaves", DEFAULT_OCTAVES, Field::value)?,
+            },
+            "turbulence" => Pattern::Turbulence {
+                octaves: block.optional_or("octaves", DEFAULT_OCTAVES, Field::value)?,
+            },
+            "marble" => Pattern::Marble,
+            "wood" => Pattern::Wood,
+            "worley" => Pattern::Worley,
+            other => {
+                return Err(kind.error(&format!(
+                    "expected checker, stripes, grid, gradient, noise, fbm, turbulence, marble, \
+                     wood or worley but found `{}`",
+                    other
+                )))
+            }
+        };
+        let white = Color {
+            red: 1.0,
+            green: 1.0,
+            blue: 1.0,
+        };
+        let colors = [
+            block.optional_or("color_a", Color::black(), Field::color)?,
+            block.optional_or("color_b", white, Field::color)?,
+        ];
+        let space = match block.optional("space")? {
+            Some(field) if field.single()? == "solid" => PatternSpace::Solid {
+                scale: block.optional_or("scale", 1.0, Field::value)?,
+            },
+            Some(field) if field.single()? != "surface" => {
+                return Err(field.error(&format!(
+                    "expected surface or solid but found `{}`",
+                    field.single()?
+                )))
+            }
+            _ => {
+                let default = UvTransform::default();
+                PatternSpace::Surface(UvTransform {
+                    scale: block.optional_or("scale", default.scale, Field::scale)?,
+                    rotation: block.optional_or("rotation", default.rotation, Field::value)?,
+                    offset: block.optional_or("offset", default.offset, Field::pair)?,
+                })
+            }
+        };
+        block.finish()?;
+        Ok(Procedural {
+            pattern,
+            colors,
+            space,
+        })
+    }
+
+    // An element's material: either a reference to a named material or a nested material block
+    fn element_material(
+        &self,
+        block: &mut Block,
+        materials: &HashMap<String, Material>,
+    ) -> Result<Material> {
+        self.optional_element_material(block, materials)?
+            .ok_or_else(|| block.missing("material"))
+    }
+
+    fn optional_element_material(
+        &self,
+        block: &mut Block,
+        materials: &HashMap<String, Material>,
+    ) -> Result<Option<Material>> {
+        let nested = block.take_block("material")?;
+        match (block.optional("material")?, nested) {
+            (Some(field), None) => {
+                let name = field.single()?;
+                let material = materials.get(name).cloned().ok_or_else(|| {
+                    field.error(&format!("no material named `{}` has been defined", name))
+                })?;
+                Ok(Some(material))
+            }
+            (None, Some(nested)) => self.material(nested).map(Some),
+            (Some(field), Some(_)) => {
+                Err(field.error("give either a material name or a material block, not both"))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn sphere(&self, mut block: Block, materials: &HashMap<String, Material>) -> Result<Sphere> {
+        let sphere = Sphere {
+            center: block.required("center")?.point()?,
+            radius: block.required("radius")?.positive()?,
+            material: self.element_material(&mut block, materials)?,
+        };
+        block.finish()?;
+        Ok(sphere)
+    }
+
+    fn plane(&self, mut block: Block, materials: &HashMap<String, Material>) -> Result<Plane> {
+        let plane = Plane {
+            origin: block.required("origin")?.point()?,
+            // Shading assumes a unit normal, so any length is accepted but only the direction used
+            normal: block.required("normal")?.vector()?.normalize(),
+            material: self.element_material(&mut block, materials)?,
+        };
+        block.finish()?;
+        Ok(plane)
+    }
+
+    fn triangle(
+        &self,
+        mut block: Block,
+        materials: &HashMap<String, Material>,
+    ) -> Result<Triangle> {
+        let vertices = block.all("vertex");
+        if vertices.len() != 3 {
+            return Err(block.error_at(
+                "vertex",
+                &format!("a triangle needs 3 vertices but found {}", vertices.len()),
+            ));
+        }
+        let triangle = Triangle {
+            vertices: [
+                vertices[0].vertex()?,
+                vertices[1].vertex()?,
+                vertices[2].vertex()?,
+            ],
+            material: self.element_material(&mut block, materials)?,
+        };
+        block.finish()?;
+        Ok(triangle)
+    }
+
+    fn mesh(&self, mut block: Block, materials: &HashMap<String, Material>) -> Result<Mesh> {
+        let vertices = block
+            .all("vertex")
+            .iter()
+            .map(Field::vertex)
+            .collect::<Result<Vec<_>>>()?;
+        let faces = block
+            .all("face")
+            .iter()
+            .map(|field| {
+                let (a, b, c) = field.triple()?;
+                if [a, b, c].iter().any(|&i| i >= vertices.len()) {
+                    return Err(field.error(&format!(
+                        "vertex index out of range; the mesh has {} vertices",
+                        vertices.len()
+                    )));
+                }
+                Ok([a, b, c])
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if faces.is_empty() {
+            return Err(block.missing("face"));
+        }
+        let mesh = Mesh {
+            vertices: Arc::new(vertices),
+            faces,
+            material: self.element_material(&mut block, materials)?,
+            bvh: None,
+        };
+        block.finish()?;
+        Ok(mesh)
+    }
+
+    // A model from an OBJ file, as one mesh per material. The material given here is used for the
+    // faces that the model's MTL files don't cover.
+    fn obj(&self, mut block: Block, materials: &HashMap<String, Material>) -> Result<Vec<Element>> {
+        let file = block.required("file")?;
+        let default_material = self
+            .optional_element_material(&mut block, materials)?
+            .unwrap_or(Material {
+                coloration: Coloration::Color(Color {
+                    red: 1.0,
+                    green: 1.0,
+                    blue: 1.0,
+                }),
+                albedo: Parameter::Constant(1.0),
+                surface: SurfaceType::Diffuse,
+                specular_color: Color::black(),
+                specular_exponent: Parameter::Constant(32.0),
+                normal_map: None,
+            });
+        let elements = obj::load(
+            self.base_dir.join(file.single()?),
+            &default_material,
+            &self.textures,
+        )
+        .map_err(|e| file.error(&e.to_string()))?;
+        block.finish()?;
+        Ok(elements)
+    }
+
+    fn directional_light(&self, mut block: Block) -> Result<Light> {
+        let light = DirectionalLight {
+            direction: block.required("direction")?.vector()?,
+            color: block.required("color")?.color()?,
+            intensity: block.required("intensity")?.value()?,
+        };
+        block.finish()?;
+        Ok(Light::Directional(light))
+    }
+
+    fn spherical_light(&self, mut block: Block) -> Result<Light> {
+        let light = SphericalLight {
+            position: block.required("position")?.point()?,
+            radius: block.optional_or("radius", 0.0, Field::value)?,
+            color: block.required("color")?.color()?,
+            intensity: block.required("intensity")?.value()?,
+            samples: block.optional_or("samples", DEFAULT_LIGHT_SAMPLES, Field::value)?,
+        };
+        block.finish()?;
+        Ok(Light::Spherical(light))
+    }
+
+    fn spot_light(&self, mut block: Block) -> Result<Light> {
+        let position = block.required("position")?.point()?;
+        let direction = block.required("direction")?.vector()?;
+        let inner_angle = block.required("inner_angle")?.value()?;
+        let outer_field = block.required("outer_angle")?;
+        let outer_angle = outer_field.value()?;
+        if outer_angle < inner_angle {
+            return Err(outer_field.error("outer_angle can't be less than inner_angle"));
+        }
+        let light = SpotLight {
+            position,
+            direction,
+            inner_angle,
+            outer_angle,
+            falloff: block.optional_or("falloff", 1.0, Field::value)?,
+            color: block.required("color")?.color()?,
+            intensity: block.required("intensity")?.value()?,
+        };
+        block.finish()?;
+        Ok(Light::Spot(light))
+    }
+
+    fn rectangle_light(&self, mut block: Block) -> Result<Light> {
+        let position = block.required("position")?.point()?;
+        let u = block.required("u")?.vector()?;
+        let v_field = block.required("v")?;
+        let v = v_field.vector()?;
+        if u.cross(&v).length() == 0.0 {
+            return Err(v_field.error("u and v must not be parallel"));
+        }
+        let light = RectangleLight {
+            position,
+            u,
+            v,
+            color: block.required("color")?.color()?,
+            intensity: block.required("intensity")?.value()?,
+            samples: block.optional_or("samples", DEFAULT_LIGHT_SAMPLES, Field::value)?,
+        };
+        block.finish()?;
+        Ok(Light::Rectangle(light))
+    }
+
+    fn disc_light(&self, mut block: Block) -> Result<Light> {
+        let light = DiscLight {
+            position: block.required("position")?.point()?,
+            normal: block.required("normal")?.vector()?,
+            radius: block.required("radius")?.value()?,
+            color: block.required("color")?.color()?,
+            intensity: block.required("intensity")?.value()?,
+            samples: block.optional_or("samples", DEFAULT_LIGHT_SAMPLES, Field::value)?,
+        };
+        block.finish()?;
+        Ok(Light::Disc(light))
+    }
+}
+
+// Any of the TEXTURE_SETTINGS given in a material block. Those left out keep the texture's
+// defaults.
+#[derive(Default)]
+struct TextureSettings {
+    filter: Option<TextureFilter>,
+    addressing: Option<TextureAddressing>,
+    scale: Option<(f32, f32)>,
+    rotation: Option<f32>,
+    offset: Option<(f32, f32)>,
+}
+
+impl TextureSettings {
+    fn read(block: &mut Block) -> Result<TextureSettings> {
+        Ok(TextureSettings {
+            filter: block
+                .optional("texture_filter")?
+                .map(|f| f.texture_filter())
+                .transpose()?,
+            addressing: block
+                .optional("texture_addressing")?
+                .map(|f| f.texture_addressing())
+                .transpose()?,
+            scale: block
+                .optional("texture_scale")?
+                .map(|f| f.scale())
+                .transpose()?,
+            rotation: block
+                .optional("texture_rotation")?
+                .map(|f| f.value())
+                .transpose()?,
+            offset: block
+                .optional("texture_offset")?
+                .map(|f| f.pair())
+                .transpose()?,
+        })
+    }
+
+    fn apply(&self, texture: &mut Texture) {
+        let transform = texture.transform;
+        texture.filter = self.filter.unwrap_or(texture.filter);
+        texture.addressing = self.addressing.unwrap_or(texture.addressing);
+        texture.transform = UvTransform {
+            scale: self.scale.unwrap_or(transform.scale),
+            rotation: self.rotation.unwrap_or(transform.rotation),
+            offset: self.offset.unwrap_or(transform.offset),
+        };
+    }
+}
+
+// Where in the file something was given: a field, or a block that's been consumed
+enum Given {
+    Field(Field),
+    Block { kind: String, line: usize },
+}
+
+impl Given {
+    fn error(&self, message: &str) -> SceneError {
+        match *self {
+            Given::Field(ref field) => field.error(message),
+            Given::Block { ref kind, line } => SceneError::Parse {
+                line,
+                field: kind.clone(),
+                message: message.to_string(),
+            },
+        }
+    }
+}
+
+// `key value value ...`
+#[derive(Debug)]
+struct Field {
+    key: String,
+    values: Vec<String>,
+    line: usize,
+}
+
+impl Field {
+    fn error(&self, message: &str) -> SceneError {
+        SceneError::Parse {
+            line: self.line,
+            field: self.key.clone(),
+            message: message.to_string(),
+        }
+    }
+
+    fn expect_count(&self, count: usize) -> Result<()> {
+        if self.values.len() == count {
+            Ok(())
+        } else {
+            Err(self.error(&format!(
+                "expected {} value{} but found {}",
+                count,
+                if count == 1 { "" } else { "s" },
+                self.values.len()
+            )))
+        }
+    }
+
+    fn single(&self) -> Result<&str> {
+        self.expect_count(1)?;
+        Ok(&self.values[0])
+    }
+
+    fn parse_at<T: FromStr>(&self, index: usize) -> Result<T> {
+        let value = &self.values[index];
+        // Rust reads inf and NaN as numbers, but nothing in a scene can use them
+        if value.parse::<f64>().is_ok_and(|v| !v.is_finite()) {
+            return Err(self.error(&format!("expected a finite number but found `{}`", value)));
+        }
+        value
+            .parse()
+            .map_err(|_| self.error(&format!("expected a number but found `{}`", value)))
+    }
+
+    fn value<T: FromStr>(&self) -> Result<T> {
+        self.expect_count(1)?;
+        self.parse_at(0)
+    }
+
+    // A single value greater than zero, e.g. a size
+    fn positive<T: FromStr + PartialOrd + Default>(&self) -> Result<T> {
+        let value = self.value()?;
+        if value > T::default() {
+            Ok(value)
+        } else {
+            Err(self.error("must be greater than zero"))
+        }
+    }
+
+    // Degrees, strictly between 0 and 180: a wider view can't be projected onto a flat image
+    fn fov(&self) -> Result<f64> {
+        let fov = self.value()?;
+        if fov > 0.0 && fov < 180.0 {
+            Ok(fov)
+        } else {
+            Err(self.error("must be between 0 and 180 degrees (exclusive)"))
+        }
+    }
+
+    fn triple<T: FromStr>(&self) -> Result<(T, T, T)> {
+        self.expect_count(3)?;
+        Ok((self.parse_at(0)?, self.parse_at(1)?, self.parse_at(2)?))
+    }
+
+    fn point(&self) -> Result<Point> {
+        let (x, y, z) = self.triple()?;
+        Ok(Point { x, y, z })
+    }
+
+    fn vector(&self) -> Result<Vector3> {
+        let (x, y, z) = self.triple()?;
+        let vector = Vector3 { x, y, z };
+        if vector.length() == 0.0 {
+            return Err(self.error("vector must not be zero length"));
+        }
+        Ok(vector)
+    }
+
+    // `x y z [normal x y z] [uv u v]`
+    fn vertex(&self) -> Result<Vertex> {
+        if self.values.len() < 3 {
+            return Err(self.error("expected a position: x y z"));
+        }
+        let mut vertex = Vertex {
+            position: Point {
+                x: self.parse_at(0)?,
+                y: self.parse_at(1)?,
+                z: self.parse_at(2)?,
+            },
+            normal: None,
+            texture_coords: None,
+        };
+        let mut i = 3;
+        while i < self.values.len() {
+            match self.values[i].as_str() {
+                "normal" if vertex.normal.is_none() && i + 3 < self.values.len() => {
+                    vertex.normal = Some(
+                        Vector3 {
+                            x: self.parse_at(i + 1)?,
+                            y: self.parse_at(i + 2)?,
+                            z: self.parse_at(i + 3)?,
+                        }
+                        .normalize(),
+                    );
+                    i += 4;
+                }
+                "uv" if vertex.texture_coords.is_none() && i + 2 < self.values.len() => {
+                    vertex.texture_coords = Some(TextureCoords {
+                        x: self.parse_at(i + 1)?,
+                        y: self.parse_at(i + 2)?,
+                    });
+                    i += 3;
+                }
+                other => {
+                    return Err(self.error(&format!(
+                        "expected `normal x y z` or `uv u v` after the position but found `{}`",
+                        other
+                    )))
+                }
+            }
+        }
+        Ok(vertex)
+    }
+
+    fn color(&self) -> Result<Color> {
+        self.expect_count(3)?;
+        Ok(Color {
+            red: self.parse_at(0)?,
+            green: self.parse_at(1)?,
+            blue: self.parse_at(2)?,
+        })
+    }
+
+    fn pair<T: FromStr>(&self) -> Result<(T, T)> {
+        self.expect_count(2)?;
+        Ok((self.parse_at(0)?, self.parse_at(1)?))
+    }
+
+    // `s` or `x y`
+    fn scale(&self) -> Result<(f32, f32)> {
+        if self.values.len() == 1 {
+            let scale = self.parse_at(0)?;
+            Ok((scale, scale))
+        } else {
+            self.pair()
+        }
+    }
+
+    // `repeat`, `mirror`, `clamp` or `border r g b`
+    fn texture_addressing(&self) -> Result<TextureAddressing> {
+        match self.values.first().map(String::as_str) {
+            Some("repeat") if self.values.len() == 1 => Ok(TextureAddressing::Repeat),
+            Some("mirror") if self.values.len() == 1 => Ok(TextureAddressing::Mirror),
+            Some("clamp") if self.values.len() == 1 => Ok(TextureAddressing::Clamp),
+            Some("border") if self.values.len() == 4 => Ok(TextureAddressing::Border(Color {
+                red: self.parse_at(1)?,
+                green: self.parse_at(2)?,
+                blue: self.parse_at(3)?,
+            })),
+            _ => Err(self.error("expected repeat, mirror, clamp or border r g b")),
+        }
+    }
+
+    fn texture_filter(&self) -> Result<TextureFilter> {
+        match self.single()? {
+            "nearest" => Ok(TextureFilter::Nearest),
+            "bilinear" => Ok(TextureFilter::Bilinear),
+            "trilinear" => Ok(TextureFilter::Trilinear),
+            "anisotropic" => Ok(TextureFilter::Anisotropic),
+            other => Err(self.error(&format!(
+                "expected nearest, bilinear, trilinear or anisotropic but found `{}`",
+                other
+            ))),
+        }
+    }
+
+    fn fov_axis(&self) -> Result<FovAxis> {
+        match self.single()? {
+            "horizontal" => Ok(FovAxis::Horizontal),
+            "vertical" => Ok(FovAxis::Vertical),
+            "diagonal" => Ok(FovAxis::Diagonal),
+            other => Err(self.error(&format!(
+                "expected horizontal, vertical or diagonal but found `{}`",
+                other
+            ))),
+        }
+    }
+}
+
+// `kind [name] { ... }`
+#[derive(Debug)]
+struct Block {
+    kind: String,
+    name: Option<String>,
+    line: usize,
+    fields: Vec<Field>,
+    blocks: Vec<Block>,
+}
+
+impl Block {
+    fn error(&self, message: &str) -> SceneError {
+        self.error_at(&self.kind, message)
+    }
+
+    fn error_at(&self, field: &str, message: &str) -> SceneError {
+        SceneError::Parse {
+            line: self.line,
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn missing(&self, field: &str) -> SceneError {
+        self.error_at(field, &format!("missing from {}", self.kind))
+    }
+
+    fn name(&self) -> Result<String> {
+        self.name
+            .clone()
+            .ok_or_else(|| self.error(&format!("{} needs a name", self.kind)))
+    }
+
+    // Removes and returns the field called `key`, which may appear at most once
+    fn optional(&mut self, key: &str) -> Result<Option<Field>> {
+        let mut matching = self.fields.iter().filter(|f| f.key == key);
+        if let (Some(_), Some(duplicate)) = (matching.next(), matching.next()) {
+            return Err(duplicate.error("field given more than once"));
+        }
+        Ok(self
+            .fields
+            .iter()
+            .position(|f| f.key == key)
+            .map(|i| self.fields.remove(i)))
+    }
+
+    fn optional_or<T>(
+        &mut self,
+        key: &str,
+        default: T,
+        convert: impl Fn(&Field) -> Result<T>,
+    ) -> Result<T> {
+        match self.optional(key)? {
+            Some(field) => convert(&field),
+            None => Ok(default),
+        }
+    }
+
+    // Whether there's a field called `key`, leaving it in place
+    fn contains(&self, key: &str) -> bool {
+        self.fields.iter().any(|f| f.key == key)
+    }
+
+    // Removes and returns every field called `key`, for fields that can be repeated
+    fn all(&mut self, key: &str) -> Vec<Field> {
+        let (matching, rest) = std::mem::take(&mut self.fields)
+            .into_iter()
+            .partition(|f| f.key == key);
+        self.fields = rest;
+        matching
+    }
+
+    fn required(&mut self, key: &str) -> Result<Field> {
+        self.optional(key)?.ok_or_else(|| self.missing(key))
+    }
+
+    fn take_block(&mut self, kind: &str) -> Result<Option<Block>> {
+        let mut matching = self.blocks.iter().filter(|b| b.kind == kind);
+        if let (Some(_), Some(duplicate)) = (matching.next(), matching.next()) {
+            return Err(duplicate.error("block given more than once"));
+        }
+        Ok(self
+            .blocks
+            .iter()
+            .position(|b| b.kind == kind)
+            .map(|i| self.blocks.remove(i)))
+    }
+
+    fn take_blocks(&mut self) -> Vec<Block> {
+        std::mem::take(&mut self.blocks)
+    }
+
+    // Anything left over wasn't asked for by the loader, which means it's a typo or in the wrong
+    // place
+    fn finish(self) -> Result<()> {
+        if let Some(field) = self.fields.first() {
+            return Err(field.error(&format!("unknown field in {}", self.kind)));
+        }
+        if let Some(block) = self.blocks.first() {
+            return Err(block.error(&format!("unexpected block in {}", self.kind)));
+        }
+        Ok(())
+    }
+}
+
+// Splits a line into whitespace separated tokens, honouring "quotes" and dropping # comments
+fn tokenize(line: &str, line_number: usize) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some(c) => token.push(c),
+                    None => {
+                        return Err(SceneError::Parse {
+                            line: line_number,
+                            field: token,
+                            message: "unterminated quote".to_string(),
+                        })
+                    }
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == '#' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_blocks(source: &str) -> Result<Block> {
+    let mut stack = vec![Block {
+        kind: "scene".to_string(),
+        name: None,
+        line: 1,
+        fields: Vec::new(),
+        blocks: Vec::new(),
+    }];
+    for (index, line) in source.lines().enumerate() {
+        let line_number = index + 1;
+        let mut tokens = tokenize(line, line_number)?;
+        let error = |field: &str, message: &str| SceneError::Parse {
+            line: line_number,
+            field: field.to_string(),
+            message: message.to_string(),
+        };
+        match tokens.last().map(|t| t.as_str()) {
+            None => {}
+            Some("{") => {
+                tokens.pop();
+                if tokens.is_empty() || tokens.len() > 2 {
+                    return Err(error("{", "expected `kind {` or `kind name {`"));
+                }
+                let name = if tokens.len() == 2 {
+                    tokens.pop()
+                } else {
+                    None
+                };
+                stack.push(Block {
+                    kind: tokens.pop().unwrap(),
+                    name,
+                    line: line_number,
+                    fields: Vec::new(),
+                    blocks: Vec::new(),
+                });
+            }
+            Some("}") => {
+                if tokens.len() != 1 {
+                    return Err(error("}", "`}` must be on a line by itself"));
+                }
+                if stack.len() == 1 {
+                    return Err(error("}", "no block to close"));
+                }
+                let block = stack.pop().unwrap();
+                stack.last_mut().unwrap().blocks.push(block);
+            }
+            Some(_) => {
+                if let Some(brace) = tokens.iter().find(|t| *t == "{" || *t == "}") {
+                    return Err(error(brace, "braces must end a line"));
+                }
+                let key = tokens.remove(0);
+                stack.last_mut().unwrap().fields.push(Field {
+                    key,
+                    values: tokens,
+                    line: line_number,
+                });
+            }
+        }
+    }
+    if stack.len() > 1 {
+        return Err(stack.pop().unwrap().error("block is never closed"));
+    }
+    Ok(stack.pop().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<Scene> {
+        Scene::parse(source, Path::new(""))
+    }
+
+    // The line, field and message of a parse error
+    fn parse_error(source: &str) -> (usize, String, String) {
+        match parse(source) {
+            Err(SceneError::Parse {
+                line,
+                field,
+                message,
+            }) => (line, field, message),
+            Err(e) => panic!("expected a parse error, got {}", e),
+            Ok(_) => panic!("expected a parse error, got a scene"),
+        }
+    }
+
+    #[test]
+    fn parses_a_scene() {
+        let scene = parse(
+            "width 4
+             height 3
+             material red {
+                 color 1 0 0
+             }
+             sphere {
+                 center 0 0 -3
+                 radius 1
+                 material red
+             }
+             plane {
+                 origin 0 -1 0
+                 normal 0 2 0
+                 material red
+             }",
+        )
+        .unwrap();
+        assert_eq!((scene.width, scene.height), (4, 3));
+        assert_eq!(scene.elements.len(), 2);
+        match scene.elements[1] {
+            Element::Plane(ref plane) => assert_eq!(plane.normal.y, 1.0),
+            ref other => panic!("expected a plane, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_the_line_and_field_at_fault() {
+        let (line, field, _) = parse_error(
+            "width 4
+             height 3
+             camera {
+                 fov 90
+                 position 0 0 0
+                 look_at 0 0 inf
+             }",
+        );
+        assert_eq!((line, field.as_str()), (6, "look_at"));
+
+        let (line, field, message) = parse_error("width 4\nheight 0\n");
+        assert_eq!((line, field.as_str()), (2, "height"));
+        assert_eq!(message, "must be greater than zero");
+
+        let (line, field, _) = parse_error(
+            "width 4
+             height 3
+             sphere {
+                 center 0 0 -3
+                 radius -1
+             }",
+        );
+        assert_eq!((line, field.as_str()), (5, "radius"));
+
+        let (line, field, _) = parse_error("width 4\nheight 3\ncamera {\n    fov 180\n}\n");
+        assert_eq!((line, field.as_str()), (4, "fov"));
+    }
+
+    #[test]
+    fn reports_camera_problems_on_the_culprit_field() {
+        let (line, field, _) = parse_error(
+            "width 4
+             height 3
+             camera {
+                 position 0 0 0
+                 look_at 0 0 -1
+                 up 0 0 1
+             }",
+        );
+        assert_eq!((line, field.as_str()), (6, "up"));
+
+        let (line, field, _) = parse_error(
+            "width 4
+             height 3
+             camera {
+                 position 1 2 3
+                 look_at 1 2 3
+             }",
+        );
+        assert_eq!((line, field.as_str()), (5, "look_at"));
+    }
+
+    #[test]
+    fn reports_surface_conflicts_on_the_field() {
+        let (line, field, _) = parse_error(
+            "width 4
+             height 3
+             material glass {
+                 color 1 1 1
+                 reflectivity 0.5
+                 refractive_index 1.5
+             }",
+        );
+        assert_eq!((line, field.as_str()), (5, "reflectivity"));
+
+        let (line, field, message) = parse_error(
+            "width 4
+             height 3
+             material glass {
+                 color 1 1 1
+                 transparency 0.5
+             }",
+        );
+        assert_eq!((line, field.as_str()), (5, "transparency"));
+        assert_eq!(message, "transparency needs a refractive_index");
+    }
+
+    #[test]
+    fn rejects_non_finite_numbers() {
+        for value in &["inf", "-inf", "NaN", "1e400"] {
+            let source = format!("width 4\nheight 3\nshadow_bias {}\n", value);
+            let (line, field, _) = parse_error(&source);
+            assert_eq!((line, field.as_str()), (3, "shadow_bias"), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+// Image textures and how they're filtered. Looking up the single nearest texel is fine while a
+// texel covers several pixels, but once a pixel covers many texels (e.g. a checkerboard floor
+// receding into the distance) which one it happens to land on jumps about from pixel to pixel,
+// giving moiré patterns that shimmer as the camera moves. The fix is to average all the texels the
+// pixel covers, which is what the mip pyramid makes cheap: each level is half the size of the one
+// before, so each texel of level n is the average of 4^n texels of the original.
+//
+// How much of the texture a pixel covers comes from the ray cone (see RayCone in rendering.rs).
+// https://www.cs.cmu.edu/afs/cs/academic/class/15869-f11/www/readings/williams83_pyramidal.pdf
+
+use crate::color::Color;
+use crate::point::Point;
+use crate::rendering::TextureCoords;
+use image::{DynamicImage, GenericImageView, ImageResult};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TextureFilter {
+    Nearest,  // the single closest texel, ignoring the footprint
+    Bilinear, // blend of the closest four texels, ignoring the footprint
+    // Bilinear lookups in the two mip levels closest in size to the footprint, blended
+    Trilinear,
+    // Footprints stretched along one direction (surfaces seen at a glancing angle) are covered
+    // with several trilinear lookups along their length, so they don't have to be blurred to the
+    // size of their longest side. Sharper than trilinear on floors and walls, but slower there.
+    Anisotropic,
+}
+
+// What the texture looks like outside the 0..1 range of texture coords
+#[derive(Copy, Clone, Debug)]
+pub enum TextureAddressing {
+    Repeat, // tiled
+    Mirror, // tiled, every other copy flipped so the edges always match up
+    Clamp,  // the edge texels stretched out forever
+    Border(Color),
+}
+
+// Applied to texture coords before the lookup, to set how big the texture is on the surface
+// (e.g. planes use world units as texture coords, so an unscaled texture is 1x1 units) and where
+// it starts, without resizing the image. Scaling by 2 fits the texture in twice.
+#[derive(Copy, Clone, Debug)]
+pub struct UvTransform {
+    pub scale: (f32, f32),
+    pub rotation: f32,      // degrees anticlockwise, after scaling
+    pub offset: (f32, f32), // after scaling and rotating
+}
+
+impl Default for UvTransform {
+    fn default() -> UvTransform {
+        UvTransform {
+            scale: (1.0, 1.0),
+            rotation: 0.0,
+            offset: (0.0, 0.0),
+        }
+    }
+}
+
+impl UvTransform {
+    pub fn apply(&self, coords: &TextureCoords) -> TextureCoords {
+        let moved = self.apply_to_difference(coords);
+        TextureCoords {
+            x: moved.x + self.offset.0,
+            y: moved.y + self.offset.1,
+        }
+    }
+
+    pub fn apply_to_differentials(
+        &self,
+        differentials: &TextureDifferentials,
+    ) -> TextureDifferentials {
+        TextureDifferentials {
+            dx: self.apply_to_difference(&differentials.dx),
+            dy: self.apply_to_difference(&differentials.dy),
+        }
+    }
+
+    // For the difference between two sets of coords, which the offset doesn't change
+    fn apply_to_difference(&self, difference: &TextureCoords) -> TextureCoords {
+        let (x, y) = (difference.x * self.scale.0, difference.y * self.scale.1);
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        TextureCoords {
+            x: x * cos - y * sin,
+            y: x * sin + y * cos,
+        }
+    }
+}
+
+// How far the texture coords move from one pixel (or sample, with several per pixel) to the next,
+// across and down the image. Zero means no filtering beyond the chosen filter's minimum.
+#[derive(Copy, Clone, Debug)]
+pub struct TextureDifferentials {
+    pub dx: TextureCoords,
+    pub dy: TextureCoords,
+}
+
+impl TextureDifferentials {
+    pub fn zero() -> TextureDifferentials {
+        let zero = TextureCoords { x: 0.0, y: 0.0 };
+        TextureDifferentials { dx: zero, dy: zero }
+    }
+}
+
+// Everything a coloration might need to know about where it's being looked up
+#[derive(Copy, Clone, Debug)]
+pub struct TextureLookup {
+    pub point: Point, // the hit point, for solid textures
+    pub coords: TextureCoords,
+    pub differentials: TextureDifferentials,
+    // Width of the ray cone at the point: about the distance between neighbouring pixels there
+    pub width: f64,
+}
+
+#[derive(Clone)]
+struct MipLevel {
+    width: u32,
+    height: u32,
+    texels: Vec<Color>, // linear, row by row
+}
+
+impl MipLevel {
+    // Texel at integer coords, which can be outside 0..width x 0..height
+    fn texel(&self, x: i64, y: i64, addressing: TextureAddressing) -> Color {
+        let (width, height) = (self.width as i64, self.height as i64);
+        let (x, y) = match addressing {
+            TextureAddressing::Repeat => (x.rem_euclid(width), y.rem_euclid(height)),
+            TextureAddressing::Mirror => (mirror(x, width), mirror(y, height)),
+            TextureAddressing::Clamp => (x.clamp(0, width - 1), y.clamp(0, height - 1)),
+            TextureAddressing::Border(color) => {
+                if x < 0 || x >= width || y < 0 || y >= height {
+                    return color;
+                }
+                (x, y)
+            }
+        };
+        self.texels[(y * width + x) as usize]
+    }
+
+    fn nearest(&self, coords: &TextureCoords, addressing: TextureAddressing) -> Color {
+        let x = (coords.x * self.width as f32).floor() as i64;
+        let y = (coords.y * self.height as f32).floor() as i64;
+        self.texel(x, y, addressing)
+    }
+
+    fn bilinear(&self, coords: &TextureCoords, addressing: TextureAddressing) -> Color {
+        // Texel centres are at half integers
+        let x = coords.x * self.width as f32 - 0.5;
+        let y = coords.y * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let texel = |x, y| self.texel(x, y, addressing);
+        let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1, y0) * fx;
+        let bottom = texel(x0, y0 + 1) * (1.0 - fx) + texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    // Half the size (rounding up), each texel averaging the 2x2 block of this level it covers
+    fn downsample(&self) -> MipLevel {
+        let width = self.width.div_ceil(2);
+        let height = self.height.div_ceil(2);
+        let mut texels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height as i64 {
+            for x in 0..width as i64 {
+                // Odd sized levels repeat their edge texels rather than wrapping around
+                let (x0, y0) = (2 * x, 2 * y);
+                let x1 = (x0 + 1).min(self.width as i64 - 1);
+                let y1 = (y0 + 1).min(self.height as i64 - 1);
+                let texel = |x, y| self.texel(x, y, TextureAddressing::Clamp);
+                let sum = texel(x0, y0) + texel(x1, y0) + texel(x0, y1) + texel(x1, y1);
+                texels.push(sum * 0.25);
+            }
+        }
+        MipLevel {
+            width,
+            height,
+            texels,
+        }
+    }
+}
+
+// Index into 0..size of a texture mirrored at every edge: 0, 1, .. size - 1, size - 1, .. 1, 0, 0, 1..
+fn mirror(i: i64, size: i64) -> i64 {
+    let i = i.rem_euclid(2 * size);
+    if i < size {
+        i
+    } else {
+        2 * size - 1 - i
+    }
+}
+
+// Most anisotropic lookups along one footprint. Longer footprints get blurrier instead.
+const MAX_ANISOTROPY: f32 = 16.0;
+
+// How an image's 8 bit values map to what the texture returns
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TextureEncoding {
+    Srgb, // colours, as images normally hold them
+    // Data, such as normal maps and masks, used as stored: 0..255 maps evenly onto 0..1
+    Linear,
+}
+
+// Decodes the image to linear values and builds its mip pyramid, full size first, down to 1x1.
+// Averaging has to happen in linear space; averaging sRGB values would darken the smaller levels.
+fn mip_levels(image: &DynamicImage, encoding: TextureEncoding) -> Vec<MipLevel> {
+    let (width, height) = image.dimensions();
+    let texels = image
+        .pixels()
+        .map(|(_, _, p)| match encoding {
+            TextureEncoding::Srgb => Color::from_rgba(p),
+            TextureEncoding::Linear => Color {
+                red: p.data[0] as f32 / 255.0,
+                green: p.data[1] as f32 / 255.0,
+                blue: p.data[2] as f32 / 255.0,
+            },
+        })
+        .collect();
+    let mut levels = vec![MipLevel {
+        width: width.max(1),
+        height: height.max(1),
+        texels,
+    }];
+    if levels[0].texels.is_empty() {
+        levels[0].texels.push(Color::black());
+    }
+    loop {
+        let last = levels.last().unwrap();
+        if last.width == 1 && last.height == 1 {
+            break;
+        }
+        let next = last.downsample();
+        levels.push(next);
+    }
+    levels
+}
+
+// An image and how to sample it. Cloning one is cheap: the texels are shared, not copied, so
+// textures made from the same image with different settings share them too.
+#[derive(Clone)]
+pub struct Texture {
+    levels: Arc<Vec<MipLevel>>,
+    pub filter: TextureFilter,
+    pub addressing: TextureAddressing,
+    pub transform: UvTransform,
+}
+
+impl std::fmt::Debug for Texture {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Not the texels
+        f.debug_struct("Texture")
+            .field("width", &self.width())
+            .field("height", &self.height())
+            .field("filter", &self.filter)
+            .field("addressing", &self.addressing)
+            .field("transform", &self.transform)
+            .finish()
+    }
+}
+
+impl Texture {
+    // Filtered trilinearly and repeated, untransformed, until the fields say otherwise. Use a
+    // TextureCache to share the decoded image between textures.
+    pub fn new(image: &DynamicImage, encoding: TextureEncoding) -> Texture {
+        Texture::with_levels(Arc::new(mip_levels(image, encoding)))
+    }
+
+    fn with_levels(levels: Arc<Vec<MipLevel>>) -> Texture {
+        Texture {
+            levels,
+            filter: TextureFilter::Trilinear,
+            addressing: TextureAddressing::Repeat,
+            transform: UvTransform::default(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.levels[0].height
+    }
+
+    // Colour of the texture around `coords`, filtered over the footprint `differentials` describe
+    pub fn sample(&self, coords: &TextureCoords, differentials: &TextureDifferentials) -> Color {
+        let coords = &self.transform.apply(coords);
+        let differentials = &self.transform.apply_to_differentials(differentials);
+        let base = &self.levels[0];
+        match self.filter {
+            TextureFilter::Nearest => base.nearest(coords, self.addressing),
+            TextureFilter::Bilinear => base.bilinear(coords, self.addressing),
+            TextureFilter::Trilinear => {
+                // The footprint's longest side in texels decides the level
+                let (dx, dy) = self.texel_lengths(differentials);
+                self.trilinear(coords, dx.max(dy))
+            }
+            TextureFilter::Anisotropic => {
+                // Trilinear lookups sized to the footprint's short side, spread along its long one
+                let (dx_length, dy_length) = self.texel_lengths(differentials);
+                let (major, major_length, minor_length) = if dx_length >= dy_length {
+                    (differentials.dx, dx_length, dy_length)
+                } else {
+                    (differentials.dy, dy_length, dx_length)
+                };
+                let minor_length = minor_length.max(major_length / MAX_ANISOTROPY);
+                let count = if minor_length > 0.0 {
+                    (major_length / minor_length)
+                        .ceil()
+                        .clamp(1.0, MAX_ANISOTROPY) as u32
+                } else {
+                    1
+                };
+                let mut color = Color::black();
+                for i in 0..count {
+                    // Evenly spaced over the footprint, centred on coords
+                    let t = (i as f32 + 0.5) / count as f32 - 0.5;
+                    let coords = TextureCoords {
+                        x: coords.x + major.x * t,
+                        y: coords.y + major.y * t,
+                    };
+                    color = color + self.trilinear(&coords, minor_length);
+                }
+                color * (count as f32).recip()
+            }
+        }
+    }
+
+    // Lengths of the two sides of the footprint, in full size texels
+    fn texel_lengths(&self, differentials: &TextureDifferentials) -> (f32, f32) {
+        let (width, height) = (self.width() as f32, self.height() as f32);
+        let length = |d: TextureCoords| (d.x * width).hypot(d.y * height);
+        (length(differentials.dx), length(differentials.dy))
+    }
+
+    // Bilinear lookups in the two levels either side of where a texel is `size` full size texels
+    // across, blended by how close each is
+    fn trilinear(&self, coords: &TextureCoords, size: f32) -> Color {
+        let last = (self.levels.len() - 1) as f32;
+        let level = size.max(1.0).log2().min(last);
+        let lower = level.floor();
+        let fraction = level - lower;
+        let color = self.levels[lower as usize].bilinear(coords, self.addressing);
+        if fraction == 0.0 {
+            return color;
+        }
+        let upper = self.levels[lower as usize + 1].bilinear(coords, self.addressing);
+        color * (1.0 - fraction) + upper * fraction
+    }
+}
+
+// Images loaded from files, each decoded once however many materials use it. Every load hands out
+// its own Texture, so each can have its own settings, but they all share the one set of texels.
+#[derive(Default)]
+pub struct TextureCache {
+    // The same image decoded as colour and as data gives different texels, so they're kept apart
+    images: Mutex<HashMap<CacheKey, Arc<Vec<MipLevel>>>>,
+}
+
+type CacheKey = (PathBuf, TextureEncoding);
+
+impl TextureCache {
+    pub fn new() -> TextureCache {
+        TextureCache::default()
+    }
+
+    pub fn load(&self, path: &Path, encoding: TextureEncoding) -> ImageResult<Texture> {
+        // The same file can be reached by different paths
+        let key = (
+            path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+            encoding,
+        );
+        let mut images = self.images.lock().unwrap();
+        let levels = match images.get(&key) {
+            Some(levels) => levels.clone(),
+            None => {
+                let levels = Arc::new(mip_levels(&image::open(path)?, encoding));
+                images.insert(key, levels.clone());
+                levels
+            }
+        };
+        Ok(Texture::with_levels(levels))
+    }
+}
@@ -0,0 +1,113 @@
+// Squeezing rendered light values, which can be arbitrarily bright, into the 0..1 range an 8 bit
+// image can show. Clipping everything over 1 turns bright areas into flat white; these operators
+// roll highlights off gradually instead. See
+// https://64.github.io/tonemapping/ for a comparison of them.
+
+use crate::color::Color;
+use crate::framebuffer::Framebuffer;
+
+#[derive(Copy, Clone, Debug)]
+pub enum ToneMapOperator {
+    // Leave values as they are, so anything over 1 is clipped. Only the exposure applies.
+    Clamp,
+    // L / (1 + L) on luminance. Never quite reaches white.
+    Reinhard,
+    // Reinhard, scaled so that `white` (or the brightest pixel if None) maps to exactly 1
+    ExtendedReinhard { white: Option<f32> },
+    // Krzysztof Narkowicz's curve fit to the ACES filmic reference transform
+    Aces,
+    // John Hable's filmic curve from Uncharted 2
+    Hable,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ToneMapping {
+    pub operator: ToneMapOperator,
+    pub exposure: f32, // in stops: each +1 doubles the brightness before the operator is applied
+}
+
+impl Default for ToneMapping {
+    fn default() -> ToneMapping {
+        ToneMapping {
+            operator: ToneMapOperator::Clamp,
+            exposure: 0.0,
+        }
+    }
+}
+
+impl ToneMapping {
+    // Returns the framebuffer mapped to 0..1 (apart from Clamp, which leaves clipping to the
+    // conversion to 8 bits)
+    pub fn apply(&self, framebuffer: &Framebuffer) -> Framebuffer {
+        let scale = 2f32.powf(self.exposure);
+        let white = match self.operator {
+            ToneMapOperator::ExtendedReinhard { white: Some(white) } => white,
+            ToneMapOperator::ExtendedReinhard { white: None } => framebuffer
+                .pixels()
+                .iter()
+                .map(|c| c.luminance() * scale)
+                .fold(0.0, f32::max),
+            _ => 0.0, // unused
+        };
+
+        let mut mapped = framebuffer.clone();
+        for pixel in mapped.pixels_mut() {
+            *pixel = self.map(*pixel * scale, white);
+        }
+        mapped
+    }
+
+    fn map(&self, color: Color, white: f32) -> Color {
+        match self.operator {
+            ToneMapOperator::Clamp => color,
+            ToneMapOperator::Reinhard => {
+                // On luminance rather than each channel, so colours keep their saturation
+                let luminance = color.luminance();
+                scale_luminance(color, luminance / (1.0 + luminance))
+            }
+            ToneMapOperator::ExtendedReinhard { .. } => {
+                let luminance = color.luminance();
+                let white_squared = (white * white).max(f32::MIN_POSITIVE);
+                let mapped = luminance * (1.0 + luminance / white_squared) / (1.0 + luminance);
+                scale_luminance(color, mapped)
+            }
+            ToneMapOperator::Aces => map_channels(color, |x| {
+                // The fit expects the input scaled by 0.6
+                let x = x * 0.6;
+                (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+            }),
+            ToneMapOperator::Hable => {
+                // 2.0 is the exposure bias from the original; 11.2 is the linear white point
+                let white_scale = hable(11.2).recip();
+                map_channels(color, |x| hable(x * 2.0) * white_scale)
+            }
+        }
+    }
+}
+
+fn hable(x: f32) -> f32 {
+    const A: f32 = 0.15; // shoulder strength
+    const B: f32 = 0.50; // linear strength
+    const C: f32 = 0.10; // linear angle
+    const D: f32 = 0.20; // toe strength
+    const E: f32 = 0.02; // toe numerator
+    const F: f32 = 0.30; // toe denominator
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+fn map_channels(color: Color, f: impl Fn(f32) -> f32) -> Color {
+    Color {
+        red: f(color.red.max(0.0)).clamp(0.0, 1.0),
+        green: f(color.green.max(0.0)).clamp(0.0, 1.0),
+        blue: f(color.blue.max(0.0)).clamp(0.0, 1.0),
+    }
+}
+
+// Scales the colour so its luminance becomes `luminance`
+fn scale_luminance(color: Color, luminance: f32) -> Color {
+    let current = color.luminance();
+    if current <= 0.0 {
+        return Color::black();
+    }
+    (color * (luminance / current)).clamp()
+}
@@ -38,6 +38,27 @@ impl Vector3 {
             z: self.x * other.y - self.y * other.x,
         }
     }
+
+    // Two unit vectors perpendicular to this one (which must be unit length) and to each other,
+    // for building a local coordinate frame around it. From "Building an Orthonormal Basis,
+    // Revisited" (Duff et al. 2017).
+    pub fn orthonormal_basis(&self) -> (Vector3, Vector3) {
+        let sign = 1.0f64.copysign(self.z);
+        let a = -1.0 / (sign + self.z);
+        let b = self.x * self.y * a;
+        (
+            Vector3 {
+                x: 1.0 + sign * self.x * self.x * a,
+                y: sign * b,
+                z: -sign * self.x,
+            },
+            Vector3 {
+                x: b,
+                y: sign + self.y * self.y * a,
+                z: -self.y,
+            },
+        )
+    }
 }
 
 impl Mul<f64> for Vector3 {